@@ -0,0 +1,38 @@
+#[macro_use]
+extern crate bencher;
+
+use bencher::{black_box, Bencher};
+use graft::StaticContext;
+
+/// A list of `n` items, each with a nested list of two children.
+fn document(n: usize) -> String {
+    let mut txt = "-- $list\n".to_string();
+    for i in 0..n {
+        txt += &format!("-- @items[] $list\nid: {}\n", i);
+        txt += "-- @items[]/items[]\nid: a\n-- @items[]/items[]\nid: b\n";
+    }
+    txt
+}
+
+fn bench(b: &mut Bencher, n: usize) {
+    let ctx = StaticContext::new(
+        "list.json",
+        r#"{"id": {"$ref": "id", "default": "root"}, "items": {"$ref": "items[]"}}"#,
+    );
+    let txt = document(n);
+    b.iter(|| black_box(graft::convert(&txt, &ctx).unwrap()));
+}
+
+// Conversion time should grow linearly: 4000 items should take about four
+// times as long as 1000.
+
+fn items_1000(b: &mut Bencher) {
+    bench(b, 1000)
+}
+
+fn items_4000(b: &mut Bencher) {
+    bench(b, 4000)
+}
+
+benchmark_group!(benches, items_1000, items_4000);
+benchmark_main!(benches);
@@ -0,0 +1,59 @@
+#[macro_use]
+extern crate bencher;
+
+use bencher::{black_box, Bencher};
+
+/// A document of `n` small sections, like our large data documents.
+fn document(n: usize) -> String {
+    let mut txt = String::new();
+    for i in 0..n {
+        txt += &format!("-- @items[] ~yaml\nid: item-{}\ntitle: Item /-- {}\n", i, i);
+    }
+    txt
+}
+
+/// The splitting `Section::parse` used to do, kept to compare against.
+fn split(txt: &str) -> usize {
+    let txt = "\n".to_owned() + txt;
+    let mut total = 0;
+    for part in txt.split("\n--").skip(1) {
+        let part = part.to_owned() + "\n";
+        let split = part.splitn(2, '\n').collect::<Vec<&str>>();
+        let body = split[1].replace("/--", "--");
+        total += split[0].len() + body.len();
+    }
+    total
+}
+
+fn bench_split(b: &mut Bencher) {
+    let txt = document(10_000);
+    b.bytes = txt.len() as u64;
+    b.iter(|| black_box(split(&txt)));
+}
+
+fn bench_scan(b: &mut Bencher) {
+    let txt = document(10_000);
+    b.bytes = txt.len() as u64;
+    b.iter(|| {
+        black_box(
+            graft::scan(&txt)
+                .map(|s| s.header.len() + s.body.len())
+                .sum::<usize>(),
+        )
+    });
+}
+
+fn bench_read(b: &mut Bencher) {
+    let txt = document(10_000);
+    b.bytes = txt.len() as u64;
+    b.iter(|| {
+        black_box(
+            graft::Reader::new(txt.as_bytes())
+                .map(|s| s.map(|s| s.header.len() + s.body.len()).unwrap())
+                .sum::<usize>(),
+        )
+    });
+}
+
+benchmark_group!(benches, bench_split, bench_scan, bench_read);
+benchmark_main!(benches);
@@ -0,0 +1,235 @@
+use crate::parser;
+use crate::section::Format;
+use crate::yaml::Anchors;
+use failure::{err_msg, Error};
+use serde_json;
+
+/// Byte range in the source, `start` inclusive and `end` exclusive.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TokenKind {
+    /// `$template`
+    Include,
+    /// `@path`
+    Reference,
+    /// `!sql`, `!sh` or `!py`
+    Process,
+    /// `~yaml`, `~md` and the like
+    Format,
+    /// `+merge`
+    Mode,
+    /// `#id`
+    Id,
+    /// `=id`
+    Alias,
+    /// `if:path`
+    If,
+    /// `unless:path`
+    Unless,
+    /// Anything else, which is an error when converting.
+    Unknown,
+}
+
+/// A whitespace separated part of a section header.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// The token as written, including its sigil.
+    pub text: String,
+    pub span: Span,
+}
+
+impl Token {
+    /// The token without its sigil, like `main` for `@main`.
+    pub fn value(&self) -> &str {
+        let sigil = match self.kind {
+            TokenKind::If => 3,
+            TokenKind::Unless => 7,
+            TokenKind::Unknown => 0,
+            _ => 1,
+        };
+        &self.text[sigil..]
+    }
+}
+
+/// Splits a header into tokens. `offset` is the position of `header` in
+/// the source, used for the spans. A leading `--` is skipped.
+pub fn tokenize(header: &str, offset: usize) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, c) in header.char_indices().chain(Some((header.len(), ' '))) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                start = None;
+                let text = &header[s..i];
+                if text == "--" {
+                    continue;
+                }
+                tokens.push(Token {
+                    kind: kind(text),
+                    text: text.to_string(),
+                    span: Span {
+                        start: offset + s,
+                        end: offset + i,
+                    },
+                });
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+fn kind(text: &str) -> TokenKind {
+    if text.starts_with("if:") {
+        return TokenKind::If;
+    }
+    if text.starts_with("unless:") {
+        return TokenKind::Unless;
+    }
+    match text.chars().next() {
+        Some('$') => TokenKind::Include,
+        Some('@') => TokenKind::Reference,
+        Some('!') => TokenKind::Process,
+        Some('~') => TokenKind::Format,
+        Some('+') => TokenKind::Mode,
+        Some('#') => TokenKind::Id,
+        Some('=') => TokenKind::Alias,
+        _ => TokenKind::Unknown,
+    }
+}
+
+/// A graft document as written, for tools like linters and formatters that
+/// work with the source rather than the converted value.
+#[derive(Debug, PartialEq)]
+pub struct Document {
+    /// Text before the first section, which is ignored when converting.
+    pub preamble: String,
+    pub sections: Vec<Section>,
+}
+
+/// A section of a document as written, before includes are resolved.
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    /// The header line, including the leading `--`.
+    pub header: String,
+    pub tokens: Vec<Token>,
+    /// The body as written, with `/--` escapes in place.
+    pub body: String,
+    /// The body parsed according to the section's format.
+    pub value: serde_json::Value,
+    /// Line of the header, 1 based.
+    pub line: usize,
+    /// The whole section, header and body.
+    pub span: Span,
+    pub header_span: Span,
+    pub body_span: Span,
+}
+
+impl Section {
+    fn token(&self, kind: TokenKind) -> Option<&Token> {
+        self.tokens.iter().rev().find(|t| t.kind == kind)
+    }
+
+    /// The `@path` of the section, `ROOT` if it has none.
+    pub fn reference(&self) -> &str {
+        self.token(TokenKind::Reference)
+            .map_or("ROOT", |t| t.value())
+    }
+
+    /// The `$template` of the section.
+    pub fn include(&self) -> Option<&str> {
+        self.token(TokenKind::Include).map(|t| t.value())
+    }
+
+    pub fn format(&self) -> Result<Format, Error> {
+        match self.token(TokenKind::Format) {
+            Some(t) => Format::from_token(&t.text),
+            None => Ok(Format::YAML),
+        }
+    }
+}
+
+/// Parses `txt` into a `Document`, without resolving includes or variables.
+pub fn parse_document(txt: &str) -> Result<Document, Error> {
+    let mut sections = vec![];
+    let mut anchors = Anchors::new();
+    for raw in parser::scan(txt) {
+        let header_end = raw.offset + raw.header.len();
+        let end = raw.body_offset + raw.body.len();
+        let mut section = Section {
+            header: raw.header.to_string(),
+            tokens: tokenize(&raw.header[2..], raw.offset + 2),
+            body: raw.body.to_string(),
+            value: serde_json::Value::Null,
+            line: raw.line,
+            span: Span {
+                start: raw.offset,
+                end,
+            },
+            header_span: Span {
+                start: raw.offset,
+                end: header_end,
+            },
+            body_span: Span {
+                start: raw.body_offset,
+                end,
+            },
+        };
+        section.value = section
+            .format()
+            .and_then(|f| f.parse_with(&raw.unescaped_body(), &mut anchors))
+            .map_err(|e| err_msg(format!("line {}: {}", raw.line, e)))?;
+        sections.push(section);
+    }
+    let preamble = match sections.first() {
+        Some(s) => txt[..s.span.start].to_string(),
+        None => txt.to_string(),
+    };
+    Ok(Document { preamble, sections })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Span, TokenKind};
+
+    #[test]
+    fn parse_document() {
+        let doc = super::parse_document("intro\n-- @main $foo ~text\nhello /-- world\n-- +merge\n")
+            .unwrap();
+        assert_eq!(doc.preamble, "intro\n");
+        assert_eq!(doc.sections.len(), 2);
+
+        let s = &doc.sections[0];
+        assert_eq!(s.reference(), "main");
+        assert_eq!(s.include(), Some("foo"));
+        assert_eq!(s.body, "hello /-- world\n");
+        assert_eq!(s.value, json!("hello -- world"));
+        assert_eq!(s.line, 2);
+        assert_eq!(
+            s.tokens.iter().map(|t| t.kind).collect::<Vec<_>>(),
+            vec![TokenKind::Reference, TokenKind::Include, TokenKind::Format]
+        );
+        assert_eq!(s.tokens[1].span, Span { start: 15, end: 19 });
+        assert_eq!(s.header_span, Span { start: 6, end: 25 });
+        assert_eq!(s.body_span, Span { start: 26, end: 42 });
+
+        let s = &doc.sections[1];
+        assert_eq!(s.reference(), "ROOT");
+        assert_eq!(s.tokens[0].kind, TokenKind::Mode);
+        assert_eq!(s.value, json!({}));
+
+        assert_eq!(
+            super::parse_document("-- ~json\n{\n")
+                .unwrap_err()
+                .to_string(),
+            "line 1: EOF while parsing an object at line 2 column 0"
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use crate::context::Context;
+use crate::convert::convert_with;
+use crate::output::{serialize, Output};
+use crate::vars::Params;
+use failure::{err_msg, Error};
+use serde_json;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MARKER: &str = "--- doc:";
+
+/// Splits a file holding several documents, each starting with a
+/// `--- doc: name` line, into names and sources. Returns `None` if `txt`
+/// has no such line.
+///
+/// Each source is padded with the text before it, blanked out, so errors
+/// report lines of the whole file and source maps its byte offsets.
+pub fn split(txt: &str) -> Result<Option<Vec<(String, String)>>, Error> {
+    let mut docs: Vec<(String, String)> = vec![];
+    let mut preamble = String::new();
+    let mut offset = 0;
+    for (idx, line) in txt.split('\n').enumerate() {
+        let start = offset;
+        offset += line.len() + 1;
+        let newline = if offset <= txt.len() { "\n" } else { "" };
+        if line.starts_with(MARKER) {
+            let name = line[MARKER.len()..].trim();
+            if name.is_empty() {
+                return Err(err_msg(format!(
+                    "line {}: document without a name",
+                    idx + 1
+                )));
+            }
+            if docs.iter().any(|(n, _)| n == name) {
+                return Err(err_msg(format!(
+                    "line {}: duplicate document: {}",
+                    idx + 1,
+                    name
+                )));
+            }
+            let end = (start + line.len() + 1).min(txt.len());
+            docs.push((name.to_string(), blank(&txt[..end])));
+            continue;
+        }
+        match docs.last_mut() {
+            Some((_, source)) => {
+                source.push_str(line);
+                source.push_str(newline);
+            }
+            None => {
+                preamble.push_str(line);
+                preamble.push_str(newline);
+            }
+        }
+    }
+    if docs.is_empty() {
+        return Ok(None);
+    }
+    if preamble.trim() != "" {
+        return Err(err_msg(format!(
+            "content before the first `{}` line",
+            MARKER
+        )));
+    }
+    Ok(Some(docs))
+}
+
+/// `txt` with all but its line breaks replaced by as many bytes of spaces.
+fn blank(txt: &str) -> String {
+    txt.chars()
+        .map(|c| match c {
+            '\n' => "\n".to_string(),
+            c => " ".repeat(c.len_utf8()),
+        })
+        .collect()
+}
+
+/// Converts a file of several `--- doc: name` documents into an object of
+/// name to value.
+pub fn convert_documents<T>(
+    txt: &str,
+    ctx: &T,
+    params: &Params,
+) -> Result<serde_json::Map<String, serde_json::Value>, Error>
+where
+    T: Context,
+{
+    let docs = split(txt)?.ok_or_else(|| err_msg(format!("no `{}` line found", MARKER)))?;
+    let mut out = serde_json::Map::new();
+    for (name, source) in docs {
+        let value = convert_with(&source, ctx, params)
+            .map_err(|e| err_msg(format!("document `{}`: {}", name, e)))?;
+        out.insert(name, value);
+    }
+    Ok(out)
+}
+
+/// Converts the graft file at `path`, which may hold several documents, in
+/// which case the value is an object of name to value.
+pub fn convert_file<T>(path: &Path, ctx: &T, params: &Params) -> Result<serde_json::Value, Error>
+where
+    T: Context,
+{
+    let txt = fs::read_to_string(path)
+        .map_err(|e| err_msg(format!("can not read {}: {}", path.display(), e)))?;
+    convert_source(&txt, ctx, params)
+}
+
+fn convert_source<T>(txt: &str, ctx: &T, params: &Params) -> Result<serde_json::Value, Error>
+where
+    T: Context,
+{
+    if split(txt)?.is_some() {
+        Ok(serde_json::Value::Object(convert_documents(
+            txt, ctx, params,
+        )?))
+    } else {
+        convert_with(txt, ctx, params)
+    }
+}
+
+/// Outcome of `convert_dir`.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The files written.
+    pub converted: Vec<PathBuf>,
+    /// The graft files that could not be converted, with the reason.
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+/// Converts every `.graft` file under `input` into a file of the `format`
+/// at the same relative path under `output`, with the name of the format as
+/// its extension, like `post.yaml`. A file that fails to convert is
+/// recorded in the report and does not stop the others.
+///
+/// Files that some file includes as a template, and that `ctx` finds under
+/// that name, are not converted, so templates may live under `input`.
+/// Symbolic links are followed, each directory once. Pass a
+/// `CachedContext` so templates are read once for the whole run.
+pub fn convert_dir<T>(
+    input: &Path,
+    output: &Path,
+    format: Output,
+    ctx: &T,
+    params: &Params,
+) -> Result<Report, Error>
+where
+    T: Context,
+{
+    let mut report = Report::default();
+    let mut files = vec![];
+    walk(input, Path::new(""), &mut HashSet::new(), &mut files, &mut report)?;
+
+    let mut sources = vec![];
+    for relative in files {
+        let path = input.join(&relative);
+        match fs::read_to_string(&path) {
+            Ok(txt) => sources.push((relative, txt)),
+            Err(e) => report.failed.push((
+                path.clone(),
+                err_msg(format!("can not read {}: {}", path.display(), e)),
+            )),
+        }
+    }
+    let mut included = HashSet::new();
+    for (_, txt) in &sources {
+        included.extend(includes(txt));
+    }
+
+    for (relative, txt) in sources {
+        let name = relative
+            .with_extension("")
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if included.contains(&name) {
+            let found = ctx.lookup(&format!("{}.graft", name));
+            if found.map_or(false, |t| t.trim() == txt.trim()) {
+                continue;
+            }
+        }
+        let target = output.join(&relative).with_extension(format.name());
+        match write(&txt, &target, format, ctx, params) {
+            Ok(()) => report.converted.push(target),
+            Err(e) => report.failed.push((input.join(relative), e)),
+        }
+    }
+    Ok(report)
+}
+
+/// Collects the paths of the `.graft` files under `dir`, relative to the
+/// directory `convert_dir` started in, which is `relative` above `dir`.
+fn walk(
+    dir: &Path,
+    relative: &Path,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+    report: &mut Report,
+) -> Result<(), Error> {
+    // a symbolic link may lead back to a directory we are in
+    if !visited.insert(fs::canonicalize(dir)?) {
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(dir)
+        .map_err(|e| err_msg(format!("can not read {}: {}", dir.display(), e)))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        let relative = relative.join(path.file_name().unwrap()); // safe because read_dir entries have names
+        if path.is_dir() {
+            if let Err(e) = walk(&path, &relative, visited, files, report) {
+                report.failed.push((path, e));
+            }
+            continue;
+        }
+        if path.extension().map_or(false, |e| e == "graft") {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+/// The templates the section headers of `txt` include, like `page` for
+/// `-- $page`.
+fn includes(txt: &str) -> Vec<String> {
+    txt.lines()
+        .filter(|line| line.starts_with("--"))
+        .flat_map(|line| line.split_whitespace())
+        .filter(|part| part.starts_with('$'))
+        .map(|part| part[1..].to_string())
+        .collect()
+}
+
+fn write<T>(txt: &str, target: &Path, format: Output, ctx: &T, params: &Params) -> Result<(), Error>
+where
+    T: Context,
+{
+    let value = convert_source(txt, ctx, params)?;
+    let bytes = serialize(&value, format)?;
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(target, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::StaticContext;
+    use crate::output::Output;
+    use crate::vars::Params;
+    use std::fs;
+    use textwrap::dedent as d;
+
+    #[test]
+    fn convert_documents() {
+        let ctx = StaticContext::new("page.json", r#"{"title": {"$ref": "title"}}"#);
+
+        let txt = d(r#"
+            --- doc: about
+            -- $page
+            title: About
+            --- doc: contact
+            -- $page
+            title: Contact"#);
+        assert_eq!(
+            super::convert_documents(&txt, &ctx, &Params::new()).unwrap(),
+            *json!({
+                "about": {"title": "About"},
+                "contact": {"title": "Contact"},
+            })
+            .as_object()
+            .unwrap()
+        );
+
+        let txt = d(r#"
+            --- doc: about
+            -- $page
+            title: About
+            --- doc: contact
+            -- $page"#);
+        assert_eq!(
+            super::convert_documents(&txt, &ctx, &Params::new())
+                .unwrap_err()
+                .to_string(),
+            "document `contact`: 'title' not found"
+        );
+        assert!(super::split("-- $page").unwrap().is_none());
+
+        let txt = "--- doc: \u{e9}\n-- $page\ntitle: A\n--- doc: b\n-- $page\ntitle: B\n";
+        let docs = super::split(txt).unwrap().unwrap();
+        let b = &docs[1].1;
+        assert_eq!(b.len(), txt.len());
+        assert_eq!(&b[txt.find("-- $page\ntitle: B").unwrap()..], "-- $page\ntitle: B\n");
+    }
+
+    #[test]
+    fn convert_dir() {
+        let ctx = StaticContext::new("page.json", r#"{"title": {"$ref": "title"}}"#);
+        let root = std::env::temp_dir().join(format!("graft-batch-{}", std::process::id()));
+        let (input, output) = (root.join("in"), root.join("out"));
+        fs::create_dir_all(input.join("blog")).unwrap();
+        fs::write(input.join("index.graft"), "-- $page\ntitle: Home\n").unwrap();
+        fs::write(input.join("blog/broken.graft"), "-- ~json\n{\n").unwrap();
+        fs::write(input.join("notes.txt"), "ignored").unwrap();
+        // a template, as the post includes it and the context has it
+        fs::write(input.join("blog/footer.graft"), "-- @footer\nbye\n").unwrap();
+        fs::write(input.join("blog/post.graft"), "-- $page\ntitle: Post\n-- $blog/footer\n").unwrap();
+        let ctx = ctx.with("blog/footer.graft", "-- @footer\nbye\n");
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&input, input.join("blog/loop")).unwrap();
+
+        let report =
+            super::convert_dir(&input, &output, Output::JSON, &ctx, &Params::new()).unwrap();
+        assert_eq!(
+            report.converted,
+            vec![output.join("blog/post.json"), output.join("index.json")]
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, input.join("blog/broken.graft"));
+        assert_eq!(
+            fs::read_to_string(output.join("blog/post.json")).unwrap(),
+            "{\n  \"title\": \"Post\"\n}\n"
+        );
+        assert!(!output.join("notes.json").exists());
+
+        let report =
+            super::convert_dir(&input, &output, Output::YAML, &ctx, &Params::new()).unwrap();
+        assert_eq!(report.converted[1], output.join("index.yaml"));
+        let yaml = fs::read_to_string(output.join("index.yaml")).unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<serde_json::Value>(&yaml).unwrap(),
+            json!({"title": "Home"})
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
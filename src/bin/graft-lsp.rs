@@ -0,0 +1,11 @@
+use std::io;
+use std::process;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(e) = graft::LanguageServer::new().run(stdin.lock(), stdout.lock()) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,5 +1,11 @@
 use failure;
-use std::{cell::RefCell, collections::HashMap, fs::File, io::Read, path::PathBuf};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
 use textwrap::dedent as d;
 
 pub trait Context {
@@ -49,6 +55,43 @@ impl DirContext {
     pub fn new(dir: PathBuf) -> DirContext {
         DirContext { dir }
     }
+
+    /// Names of the templates in the directory, like `foo` for `foo.json`
+    /// and `blog/post` for `blog/post.yml`, sorted.
+    pub fn templates(&self) -> Vec<String> {
+        let mut names = vec![];
+        collect(&self.dir, "", &mut names);
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+fn collect(dir: &Path, prefix: &str, names: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.is_dir() {
+            if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
+                collect(&path, &format!("{}{}/", prefix, name), names);
+            }
+            continue;
+        }
+        let stem = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let template = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => !stem.ends_with(".schema"),
+            Some("yml") | Some("yaml") | Some("txt") | Some("graft") => true,
+            _ => false,
+        };
+        if template {
+            names.push(format!("{}{}", prefix, stem));
+        }
+    }
 }
 
 pub struct CachedContext<Context> {
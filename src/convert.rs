@@ -1,154 +1,738 @@
 use crate::context::Context;
-use crate::section::Section;
+use crate::index::{Ancestors, Index};
+use crate::schema;
+use crate::section::{self, Section};
+use crate::sourcemap::{Mapping, SourceMap, Sources};
+use crate::toc;
+use crate::transform;
+use crate::vars::{self, Params};
 use failure::{err_msg, Error};
+use serde::de::DeserializeOwned;
 use serde_json;
+use serde_path_to_error::{self, Segment};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 
 pub fn convert<T>(txt: &str, ctx: &T) -> Result<serde_json::Value, Error>
 where
     T: Context,
 {
-    let sections = Section::parse(txt, ctx)?;
-    eval("ROOT", &sections, 0, "END")
+    convert_with(txt, ctx, &Params::new())
 }
 
-fn eval(
-    path: &str,
-    sections: &[Section],
-    start: usize,
-    till: &str,
-) -> Result<serde_json::Value, Error> {
+/// Like `convert`, with caller supplied parameters.
+pub fn convert_with<T>(txt: &str, ctx: &T, params: &Params) -> Result<serde_json::Value, Error>
+where
+    T: Context,
+{
+    let (sections, toc) = sections(txt, ctx, params)?;
+    Evaluator::new(&sections, toc)?.eval("ROOT", 0, "END", "")
+}
+
+/// Like `convert_with`, also returning a diagnostic for every section that
+/// was never used, including those ignored because an earlier section has
+/// the same reference.
+pub fn convert_with_diagnostics<T>(
+    txt: &str,
+    ctx: &T,
+    params: &Params,
+) -> Result<(serde_json::Value, Vec<Diagnostic>), Error>
+where
+    T: Context,
+{
+    let (sections, toc) = sections(txt, ctx, params)?;
+    let evaluator = Evaluator::new(&sections, toc)?;
+    let value = evaluator.eval("ROOT", 0, "END", "")?;
+    let used = evaluator.used();
+
+    let mut diagnostics = vec![];
     for (idx, section) in sections.iter().enumerate() {
-        if section.reference == till {
-            break;
-        }
-        if idx < start {
+        if used[idx] || section.reference == "END" {
             continue;
         }
-        if section.reference != path {
-            continue;
+        diagnostics.push(Diagnostic {
+            header: section.header.clone(),
+            file: section.source.clone(),
+            line: section.line,
+            reference: section.reference.clone(),
+            shadowed_by: shadowing(&sections, idx)
+                .map(|i| (sections[i].source.clone(), sections[i].line)),
+        });
+    }
+    Ok((value, diagnostics))
+}
+
+/// A section that did not contribute to the converted value.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub header: String,
+    /// The `.graft` template holding the section, `None` for the document.
+    pub file: Option<String>,
+    /// Line of the section's header, in `file` if any.
+    pub line: usize,
+    pub reference: String,
+    /// File and line of the earlier section for the same reference, which
+    /// was used instead of this one.
+    pub shadowed_by: Option<(Option<String>, usize)>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let place = |file: &Option<String>, line| match file {
+            Some(file) => format!("{} line {}", file, line),
+            None => format!("line {}", line),
+        };
+        write!(f, "section `{}` ({}): ", self.header, place(&self.file, self.line))?;
+        match self.shadowed_by {
+            Some((ref file, line)) => {
+                write!(f, "@{} is shadowed by {}", self.reference, place(file, line))
+            }
+            None => write!(f, "@{} is never used", self.reference),
         }
-        let prefix = if path == "ROOT" {
-            "".to_string()
-        } else {
-            path.to_string() + "/"
+    }
+}
+
+/// Like `convert_with`, also returning where every value in the result came
+/// from, by JSON pointer.
+pub fn convert_with_provenance<T>(
+    txt: &str,
+    ctx: &T,
+    params: &Params,
+) -> Result<(serde_json::Value, BTreeMap<String, Origin>), Error>
+where
+    T: Context,
+{
+    let (sections, toc) = sections(txt, ctx, params)?;
+    let evaluator = Evaluator::new(&sections, toc)?;
+    let value = evaluator.eval("ROOT", 0, "END", "")?;
+
+    let mut provenance = BTreeMap::new();
+    for (pointer, trace) in evaluator.trace(&value) {
+        let section = &sections[trace.idx];
+        let origin = Origin {
+            file: section.source.clone(),
+            line: section.line,
+            header: section.header.clone(),
+            template: section
+                .include
+                .as_ref()
+                .and_then(|include| section::file(include, ctx)),
+            default: trace.default,
         };
-        return digest(&section.body, sections, idx, prefix, till);
+        provenance.insert(pointer, origin);
     }
-    Err(err_msg(format!("not found: {}", path)))
+    Ok((value, provenance))
 }
 
-fn eval_list(
-    path: &str,
-    sections: &[Section],
-    start: usize,
-    till: &str,
-) -> Result<serde_json::Value, Error> {
-    let mut lst = vec![];
+/// Like `convert_with`, also returning the byte range each value in the
+/// result was written at, in the document or in a template. Values of json
+/// and yaml sources map to their own text, others to the whole section or
+/// template.
+pub fn convert_with_sourcemap<T>(
+    txt: &str,
+    ctx: &T,
+    params: &Params,
+) -> Result<(serde_json::Value, SourceMap), Error>
+where
+    T: Context,
+{
+    let (sections, toc) = sections(txt, ctx, params)?;
+    let evaluator = Evaluator::new(&sections, toc)?;
+    let value = evaluator.eval("ROOT", 0, "END", "")?;
 
-    for (idx, section) in sections.iter().enumerate() {
-        if section.reference == till {
+    let mut sources = Sources::new(txt, ctx);
+    let mut map = SourceMap::default();
+    for (pointer, trace) in evaluator.trace(&value) {
+        let section = &sections[trace.idx];
+        let template = match section.include {
+            Some(ref include) => section::file(include, ctx),
+            None => None,
+        };
+        let mapping = match template {
+            Some(file) => Mapping {
+                span: sources.template(&file).find(&trace.local),
+                file: Some(file),
+            },
+            None => Mapping {
+                file: section.source.clone(),
+                span: sources.section(section).find(&trace.local),
+            },
+        };
+        map.mappings.insert(pointer, mapping);
+    }
+    Ok((value, map))
+}
+
+/// Where a value in the converted result came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    /// The `.graft` template holding the section, `None` for the document.
+    pub file: Option<String>,
+    /// Line of the section's header.
+    pub line: usize,
+    pub header: String,
+    /// The template the value was written in, like `page.json`, if any.
+    pub template: Option<String>,
+    /// Whether the value is the `default` of a `$ref` hole.
+    pub default: bool,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "section `{}` ({} line {})", self.header, file, self.line)?,
+            None => write!(f, "section `{}` (line {})", self.header, self.line)?,
+        }
+        match (&self.template, self.default) {
+            (Some(template), true) => write!(f, ", default in {}", template),
+            (Some(template), false) => write!(f, ", from {}", template),
+            (None, true) => write!(f, ", default"),
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+/// Like `convert`, but also validates the value of every section including
+/// `$foo` against `foo.schema.json`, if the context has one.
+pub fn convert_validated<T>(txt: &str, ctx: &T) -> Result<serde_json::Value, Error>
+where
+    T: Context,
+{
+    let (sections, toc) = sections(txt, ctx, &Params::new())?;
+    let mut evaluator = Evaluator::new(&sections, toc)?;
+    evaluator.schemas = Some(ctx);
+    evaluator.eval("ROOT", 0, "END", "")
+}
+
+/// Converts and deserializes the result into `T`. If deserialization fails
+/// the error names the section that produced the offending value.
+pub fn convert_into<T, C>(txt: &str, ctx: &C) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+    C: Context,
+{
+    let (sections, toc) = sections(txt, ctx, &Params::new())?;
+    let evaluator = Evaluator::new(&sections, toc)?;
+    let value = evaluator.eval("ROOT", 0, "END", "")?;
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let mut pointer = String::new();
+        for segment in e.path().iter() {
+            match segment {
+                Segment::Seq { index } => pointer += &format!("/{}", index),
+                Segment::Map { key } => pointer += &format!("/{}", schema::escape(key)),
+                Segment::Enum { variant } => pointer += &format!("/{}", schema::escape(variant)),
+                Segment::Unknown => pointer += "/?",
+            }
+        }
+        let shown: &str = if pointer == "" { "/" } else { &pointer };
+        match evaluator.origin(&pointer) {
+            Some(section) => err_msg(format!(
+                "section `{}` (line {}): {}: {}",
+                section.header,
+                section.line,
+                shown,
+                e.inner()
+            )),
+            None => err_msg(format!("{}: {}", e.path(), e.inner())),
+        }
+    })
+}
+
+/// Parses `txt` and prepares the sections for evaluation, returning them
+/// with the document's table of contents.
+pub fn sections(
+    txt: &str,
+    ctx: &Context,
+    params: &Params,
+) -> Result<(Vec<Section>, serde_json::Value), Error> {
+    let mut sections = Section::parse(txt, ctx)?;
+    vars::interpolate(&mut sections, params)?;
+    section::render(&mut sections);
+    let toc = toc::anchor(&mut sections);
+    Ok((sections, toc))
+}
+
+/// Converts `sections` and returns, for each, whether it was used.
+pub fn usage(sections: &[Section], toc: serde_json::Value) -> Result<Vec<bool>, Error> {
+    let evaluator = Evaluator::new(sections, toc)?;
+    evaluator.eval("ROOT", 0, "END", "")?;
+    Ok(evaluator.used())
+}
+
+/// The earlier section for the same reference that `eval` picks instead of
+/// the one at `idx`, if any: the first one after the closest section above
+/// it for a parent reference. List items and `[N]` patches are never
+/// shadowed.
+pub fn shadowing(sections: &[Section], idx: usize) -> Option<usize> {
+    let reference = &sections[idx].reference;
+    if reference.ends_with(']') {
+        return None;
+    }
+    let mut first = None;
+    for (i, section) in sections[..idx].iter().enumerate().rev() {
+        if &section.reference == reference {
+            first = Some(i);
+        } else if reference.starts_with(&(section.reference.to_string() + "/")) {
             break;
         }
-        if idx < start {
-            continue;
+    }
+    first
+}
+
+struct Evaluator<'a> {
+    sections: &'a [Section],
+    index: Index<'a>,
+    schemas: Option<&'a Context>,
+    /// JSON pointer where each evaluated section ended up, with its index.
+    origins: RefCell<Vec<(String, usize)>>,
+    /// JSON pointers of the values taken from the `default` of a hole.
+    defaults: RefCell<Vec<String>>,
+    /// JSON pointers of the list items inserted by `[+N]` sections.
+    inserted: RefCell<Vec<String>>,
+    /// Index of the section marked with each `#id`.
+    ids: HashMap<String, usize>,
+    /// Ids being evaluated through a reference, to catch cycles.
+    anchors: RefCell<Vec<String>>,
+    /// Value of `{"$ref": "@toc"}`.
+    toc: serde_json::Value,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(sections: &'a [Section], toc: serde_json::Value) -> Result<Evaluator<'a>, Error> {
+        Ok(Evaluator {
+            sections,
+            index: Index::new(sections),
+            schemas: None,
+            origins: RefCell::new(vec![]),
+            defaults: RefCell::new(vec![]),
+            inserted: RefCell::new(vec![]),
+            ids: Section::ids(sections)?,
+            anchors: RefCell::new(vec![]),
+            toc,
+        })
+    }
+
+    /// Whether each section was evaluated so far.
+    fn used(&self) -> Vec<bool> {
+        let mut used = vec![false; self.sections.len()];
+        for (_, idx) in self.origins.borrow().iter() {
+            used[*idx] = true;
         }
-        if section.reference != path {
-            if path.starts_with(section.reference.as_str()) {
-                break;
+        used
+    }
+
+    /// Finds the section that produced the value at `pointer`, or the
+    /// closest value containing it.
+    fn origin(&self, pointer: &str) -> Option<&'a Section> {
+        self.origins
+            .borrow()
+            .iter()
+            .filter(|(p, _)| pointer == p || pointer.starts_with(&(p.to_string() + "/")))
+            .max_by_key(|(p, _)| p.len())
+            .map(|(_, idx)| &self.sections[*idx])
+    }
+
+    /// Finds, for every value in `value`, the section that produced it and
+    /// its pointer in that section's body or template.
+    fn trace(&self, value: &serde_json::Value) -> BTreeMap<String, Trace> {
+        // later entries win, like an alias and the section it refers to
+        let origins = self.origins.borrow();
+        let producers: HashMap<&str, usize> =
+            origins.iter().map(|(p, idx)| (p.as_str(), *idx)).collect();
+        let defaults = self.defaults.borrow();
+        let defaults: HashSet<&str> = defaults.iter().map(|p| p.as_str()).collect();
+        let inserted = self.inserted.borrow();
+        let inserted: HashSet<&str> = inserted.iter().map(|p| p.as_str()).collect();
+
+        let mut traces = BTreeMap::new();
+        let mut stack = vec![(value, String::new(), None::<Trace>, String::new())];
+        while let Some((value, pointer, parent, key)) = stack.pop() {
+            let trace = if let Some(idx) = producers.get(pointer.as_str()) {
+                Trace {
+                    idx: *idx,
+                    local: String::new(),
+                    default: false,
+                }
+            } else if let Some(parent) = parent {
+                let local = parent.local + "/" + &key;
+                if defaults.contains(pointer.as_str()) {
+                    Trace {
+                        idx: parent.idx,
+                        local: local + "/default",
+                        default: true,
+                    }
+                } else {
+                    Trace { local, ..parent }
+                }
+            } else {
+                continue;
+            };
+            match value {
+                serde_json::Value::Object(o) => {
+                    for (k, v) in o {
+                        let k = schema::escape(k);
+                        let pointer = pointer.to_string() + "/" + &k;
+                        stack.push((v, pointer, Some(trace.clone()), k));
+                    }
+                }
+                serde_json::Value::Array(a) => {
+                    // items after an inserted one are shifted in the output
+                    let mut shift = 0;
+                    for (i, v) in a.iter().enumerate() {
+                        let pointer = format!("{}/{}", pointer, i);
+                        if inserted.contains(pointer.as_str()) {
+                            shift += 1;
+                        }
+                        let key = (i - shift.min(i)).to_string();
+                        stack.push((v, pointer, Some(trace.clone()), key));
+                    }
+                }
+                _ => {}
             }
-            continue;
+            traces.insert(pointer, trace);
+        }
+        traces
+    }
+
+    fn eval(
+        &self,
+        path: &str,
+        start: usize,
+        till: &str,
+        pointer: &str,
+    ) -> Result<serde_json::Value, Error> {
+        match self.index.range(path, start, self.index.end(till)).first() {
+            Some(idx) => self.value(*idx, till, pointer),
+            None => Err(err_msg(format!("not found: {}", path))),
         }
-        let prefix = if path == "ROOT" {
+    }
+
+    fn eval_list(
+        &self,
+        path: &str,
+        start: usize,
+        till: &str,
+        pointer: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let mut lst = vec![];
+        let limit = self.index.limit(path, start, till, Ancestors::Prefixes);
+        for idx in self.index.range(path, start, limit) {
+            let pointer = format!("{}/{}", pointer, lst.len());
+            lst.push(self.value(*idx, till, &pointer)?);
+        }
+        Ok(serde_json::Value::Array(lst))
+    }
+
+    /// Evaluates the section at `idx`, which ends up at `pointer`.
+    fn value(&self, idx: usize, till: &str, pointer: &str) -> Result<serde_json::Value, Error> {
+        let section = &self.sections[idx];
+        self.origins.borrow_mut().push((pointer.to_string(), idx));
+        if let Some(ref id) = section.alias {
+            return self.anchor(id, pointer);
+        }
+        let prefix = if section.reference == "ROOT" {
             "".to_string()
         } else {
-            path.to_string() + "/"
+            section.reference.to_string() + "/"
         };
-        lst.push(digest(&section.body, sections, idx, prefix, till)?);
+        let value = self.digest(&section.body, idx, prefix, till, pointer)?;
+        self.validate(section, &value)?;
+        Ok(value)
     }
 
-    Ok(serde_json::Value::Array(lst))
-}
+    /// Evaluates the section marked `#id`, wherever it is in the document.
+    fn anchor(&self, id: &str, pointer: &str) -> Result<serde_json::Value, Error> {
+        let idx = *self
+            .ids
+            .get(id)
+            .ok_or_else(|| err_msg(format!("unknown id: #{}", id)))?;
+        if let Some(pos) = self.anchors.borrow().iter().position(|a| a == id) {
+            let mut chain: Vec<String> = self.anchors.borrow()[pos..]
+                .iter()
+                .map(|a| format!("#{}", a))
+                .collect();
+            chain.push(format!("#{}", id));
+            return Err(err_msg(format!("reference cycle: {}", chain.join(" -> "))));
+        }
+        self.anchors.borrow_mut().push(id.to_string());
+        let value = self.value(idx, "END", pointer);
+        self.anchors.borrow_mut().pop();
+        value
+    }
 
-fn digest(
-    body: &serde_json::Value,
-    sections: &[Section],
-    start: usize,
-    prefix: String,
-    till: &str,
-) -> Result<serde_json::Value, Error> {
-    if let serde_json::Value::Object(o) = body {
-        let mut n = serde_json::Map::new();
-        for (k, v) in o {
-            if !v.is_object() {
-                n.insert(
-                    k.to_string(),
-                    digest(v, sections, start, prefix.clone(), till)?,
-                );
-                continue;
+    fn digest(
+        &self,
+        body: &serde_json::Value,
+        start: usize,
+        prefix: String,
+        till: &str,
+        pointer: &str,
+    ) -> Result<serde_json::Value, Error> {
+        if let serde_json::Value::Object(o) = body {
+            let mut n = serde_json::Map::new();
+            for (k, v) in o {
+                let pointer = pointer.to_string() + "/" + &schema::escape(k);
+                if !v.is_object() {
+                    n.insert(
+                        k.to_string(),
+                        self.digest(v, start, prefix.clone(), till, &pointer)?,
+                    );
+                    continue;
+                }
+                let ov = v.as_object().unwrap(); // safe because we have already checked
+                if !ov.contains_key("$ref") {
+                    n.insert(
+                        k.to_string(),
+                        self.digest(v, start, prefix.clone(), till, &pointer)?,
+                    );
+                    continue;
+                }
+
+                let ref_ = ov.get("$ref").unwrap(); // safe because we have already checked
+                if !ref_.is_string() {
+                    return Err(err_msg(format!("$ref if not a string: {:?}", &ref_)));
+                }
+
+                let ref_ = ref_.as_str().unwrap(); // safe because we have already checked
+                if ref_.starts_with('#') {
+                    n.insert(k.to_string(), self.anchor(&ref_[1..], &pointer)?);
+                    continue;
+                }
+                if ref_ == "@toc" {
+                    n.insert(k.to_string(), self.toc.clone());
+                    continue;
+                }
+                let ref_ = prefix.clone() + ref_;
+
+                let default_ref = match ov.get("default_ref") {
+                    Some(serde_json::Value::String(r)) => Some(prefix.clone() + r),
+                    Some(r) => return Err(err_msg(format!("default_ref is not a string: {}", r))),
+                    None => None,
+                };
+
+                let v = if self.has_path(&ref_, start + 1, till) {
+                    self.resolve(&ref_, start + 1, till, &pointer)?
+                } else if let Some(r) = default_ref.filter(|r| self.has_path(r, start + 1, till)) {
+                    self.resolve(&r, start + 1, till, &pointer)?
+                } else if ov.get("required") == Some(&serde_json::Value::Bool(true)) {
+                    let section = &self.sections[start];
+                    let message = match ov.get("message").and_then(|m| m.as_str()) {
+                        Some(message) => message.to_string(),
+                        None => format!("'{}' is required", ref_),
+                    };
+                    return Err(err_msg(format!(
+                        "section `{}` (line {}): {}",
+                        section.header, section.line, message
+                    )));
+                } else {
+                    let default = ov
+                        .get("default")
+                        .ok_or_else(|| err_msg(format!("'{}' not found", ref_)))?;
+                    self.defaults.borrow_mut().push(pointer.clone());
+                    default.clone()
+                };
+                let v = self.patch(v, &ref_, start + 1, till, &pointer)?;
+                let v = match ov.get("transform") {
+                    Some(serde_json::Value::String(t)) => transform::apply(t, v).map_err(|e| {
+                        let section = &self.sections[start];
+                        err_msg(format!(
+                            "section `{}` (line {}): '{}': {}",
+                            section.header, section.line, ref_, e
+                        ))
+                    })?,
+                    Some(t) => return Err(err_msg(format!("transform is not a string: {}", t))),
+                    None => v,
+                };
+                n.insert(k.to_string(), v);
             }
-            let ov = v.as_object().unwrap(); // safe because we have already checked
-            if !ov.contains_key("$ref") {
-                n.insert(
-                    k.to_string(),
-                    digest(v, sections, start, prefix.clone(), till)?,
-                );
+            return Ok(serde_json::Value::Object(n));
+        } else if let serde_json::Value::Array(a) = body {
+            let mut n = vec![];
+            for (idx, item) in a.iter().enumerate() {
+                let pointer = format!("{}/{}", pointer, idx);
+                n.push(self.digest(item, start, prefix.clone(), till, &pointer)?);
+            }
+            return Ok(serde_json::Value::Array(n));
+        } else {
+            return Ok(body.clone());
+        }
+    }
+
+    fn resolve(
+        &self,
+        path: &str,
+        start: usize,
+        till: &str,
+        pointer: &str,
+    ) -> Result<serde_json::Value, Error> {
+        if path.ends_with("[]") {
+            self.eval_list(path, start, till, pointer)
+        } else {
+            self.eval(path, start, till, pointer)
+        }
+    }
+
+    fn has_path(&self, path: &str, start: usize, till: &str) -> bool {
+        let limit = self.index.limit(path, start, till, Ancestors::Parents);
+        !self.index.range(path, start, limit).is_empty() || path.ends_with("[]")
+    }
+
+    /// Applies sections like `path[2]`, `path[+2]`, `path[id=intro]` or
+    /// `path[+id=intro]`, optionally followed by `/key/...`, to the list that
+    /// `path` resolved to. Plain selectors replace the matching element or
+    /// the value at the given key in it, `+` selectors insert before it.
+    fn patch(
+        &self,
+        mut value: serde_json::Value,
+        path: &str,
+        start: usize,
+        till: &str,
+        pointer: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let base = path.trim_end_matches("[]").to_string() + "[";
+        let mut replaced = vec![];
+
+        let limit = self.index.limit(path, start, till, Ancestors::Parents);
+        for (idx, reference) in self.index.prefixed(&base, start, limit) {
+            let section = &self.sections[idx];
+            if reference[base.len()..].starts_with(']') {
                 continue;
             }
 
-            let ref_ = ov.get("$ref").unwrap(); // safe because we have already checked
-            if !ref_.is_string() {
-                return Err(err_msg(format!("$ref if not a string: {:?}", &ref_)));
+            let rest = &section.reference[base.len()..];
+            let end = rest
+                .find(']')
+                .ok_or_else(|| err_msg(format!("invalid reference: {}", section.reference)))?;
+            let (selector, tail) = (&rest[..end], &rest[end + 1..]);
+            if !tail.is_empty() && !tail.starts_with('/') {
+                return Err(err_msg(format!("invalid reference: {}", section.reference)));
+            }
+            if tail.is_empty() {
+                replaced.push(selector);
+            } else if replaced.contains(&selector) {
+                // a section replacing the whole element owns everything below it
+                continue;
             }
 
-            let ref_ = prefix.clone() + ref_.as_str().unwrap(); // safe because we have already checked
+            let insert = selector.starts_with('+');
+            let list = value.as_array_mut().ok_or_else(|| {
+                err_msg(format!("'{}' is not a list: {}", path, section.reference))
+            })?;
+            let pos = position(list, selector.trim_start_matches('+'), insert)
+                .ok_or_else(|| err_msg(format!("no element matches: {}", section.reference)))?;
+            let pointer = format!("{}/{}", pointer, pos);
 
-            let v = if has_path(&ref_, sections, start + 1, till) {
-                if ref_.ends_with("[]") {
-                    eval_list(&ref_, sections, start + 1, till)?
+            if tail.is_empty() {
+                let item = self.value(idx, till, &pointer)?;
+                if insert {
+                    self.inserted.borrow_mut().push(pointer);
+                    list.insert(pos, item);
                 } else {
-                    eval(&ref_, sections, start + 1, till)?
+                    list[pos] = item;
                 }
-            } else {
-                ov.get("default")
-                    .ok_or_else(|| err_msg(format!("'{}' not found", ref_)))?
-                    .clone()
-            };
-            n.insert(k.to_string(), v);
-        }
-        return Ok(serde_json::Value::Object(n));
-    } else if let serde_json::Value::Array(a) = body {
-        let mut n = vec![];
-        for item in a {
-            n.push(digest(item, sections, start, prefix.clone(), till)?);
+                continue;
+            }
+            if insert {
+                return Err(err_msg(format!(
+                    "can not insert at a key: {}",
+                    section.reference
+                )));
+            }
+
+            let missing =
+                |key: &str| err_msg(format!("'{}' not found: {}", key, section.reference));
+            let keys: Vec<&str> = tail[1..].split('/').collect();
+            let mut target = &mut list[pos];
+            let mut pointer = pointer;
+            for key in &keys {
+                pointer = pointer + "/" + &schema::escape(key);
+            }
+            for key in &keys[..keys.len() - 1] {
+                target = match target {
+                    serde_json::Value::Object(o) => o.get_mut(*key),
+                    serde_json::Value::Array(a) => {
+                        key.parse::<usize>().ok().and_then(move |i| a.get_mut(i))
+                    }
+                    _ => None,
+                }
+                .ok_or_else(|| missing(key))?;
+            }
+            let last = keys[keys.len() - 1];
+            let item = self.value(idx, till, &pointer)?;
+            match target {
+                serde_json::Value::Object(o) => {
+                    o.insert(last.to_string(), item);
+                }
+                serde_json::Value::Array(a) => match last.parse::<usize>() {
+                    Ok(i) if i < a.len() => a[i] = item,
+                    _ => return Err(missing(last)),
+                },
+                _ => return Err(missing(last)),
+            }
         }
-        return Ok(serde_json::Value::Array(n));
-    } else {
-        return Ok(body.clone());
+
+        Ok(value)
     }
-}
 
-fn has_path(path: &str, sections: &[Section], start: usize, till: &str) -> bool {
-    for (idx, section) in sections.iter().enumerate() {
-        if section.reference == till {
-            break;
-        }
-        if idx < start {
-            continue;
+    fn validate(&self, section: &Section, value: &serde_json::Value) -> Result<(), Error> {
+        let (ctx, include) = match (self.schemas, &section.include) {
+            (Some(ctx), Some(include)) => (ctx, include),
+            _ => return Ok(()),
+        };
+        let schema = match ctx.lookup(&format!("{}.schema.json", include)) {
+            Ok(txt) => serde_json::from_str(&txt)?,
+            Err(_) => return Ok(()),
+        };
+        let violations = schema::validate(&schema, value)
+            .map_err(|e| err_msg(format!("{}.schema.json: {}", include, e)))?;
+        if violations.is_empty() {
+            return Ok(());
         }
-        if section.reference != path {
-            if path.starts_with((section.reference.to_string()+"/").as_str()) {
-                break;
-            }
-            continue;
+        let mut msg = format!(
+            "section `{}` (line {}) does not match {}.schema.json:",
+            section.header, section.line, include
+        );
+        for v in violations {
+            let pointer: &str = if v.pointer == "" { "/" } else { &v.pointer };
+            msg += &format!("\n  {}: {}", pointer, v.message);
         }
-        return true;
+        Err(err_msg(msg))
+    }
+}
+
+/// What produced a value, see `Evaluator::trace`.
+#[derive(Clone)]
+struct Trace {
+    /// Index of the section.
+    idx: usize,
+    /// Pointer of the value in the section's body, or in its template.
+    local: String,
+    /// Whether the value is the `default` of a hole.
+    default: bool,
+}
+
+/// Finds the element of `list` a `[N]` or `[key=value]` selector refers to.
+/// When inserting, `N` may also be the length of the list.
+fn position(list: &[serde_json::Value], selector: &str, insert: bool) -> Option<usize> {
+    if let Some(i) = selector.find('=') {
+        let (key, value) = (&selector[..i], &selector[i + 1..]);
+        return list.iter().position(|item| match item.get(key) {
+            Some(serde_json::Value::String(s)) => s == value,
+            Some(other) => other.to_string() == value,
+            None => false,
+        });
     }
-    path.ends_with("[]")
+    selector
+        .parse::<usize>()
+        .ok()
+        .filter(|pos| *pos < list.len() || (insert && *pos == list.len()))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::context::StaticContext;
+    use crate::vars::Params;
     use serde_json;
     use textwrap::dedent as d;
 
@@ -256,6 +840,30 @@ mod tests {
                     "$ref": "floaters2[]"
                 }
             }"#,
+        ).with(
+            "list.json",
+            r#"{
+                "items": {
+                    "$ref": "items",
+                    "default": [
+                        {"id": "a", "title": "A"},
+                        {"id": "b", "title": "B"},
+                        {"id": "c", "title": "C"}
+                    ]
+                }
+            }"#,
+        ).with(
+            "page.json",
+            r#"{
+                "meta": {
+                    "$ref": "meta",
+                    "default": {"title": "untitled", "tags": ["a"]}
+                },
+                "layout": {"width": 10, "color": "red"},
+                "body": {
+                    "$ref": "body[]"
+                }
+            }"#,
         );
 
         t(
@@ -894,6 +1502,85 @@ mod tests {
             json!("<p>/-- @content[]/faq[] $faq_item</p>\n"),
         );
 
+        t(
+            r#"
+                -- $page +merge
+                meta:
+                  tags: [b]
+                layout:
+                  color: blue
+                body: [one]
+                -- @body[] ~text
+                two
+            "#,
+            &ctx,
+            json!({
+                "meta": {"title": "untitled", "tags": ["a", "b"]},
+                "layout": {"width": 10, "color": "blue"},
+                "body": ["one", "two"],
+            }),
+        );
+
+        t(
+            r#"
+                -- $page +merge
+                -- @meta
+                title: replaced
+            "#,
+            &ctx,
+            json!({
+                "meta": {"title": "replaced"},
+                "layout": {"width": 10, "color": "red"},
+                "body": [],
+            }),
+        );
+
+        t(
+            r#"
+                -- $list
+                -- @items[1]/title ~text
+                Bee
+                -- @items[id=c]
+                id: z
+                title: Zed
+                -- @items[+0]
+                id: first
+            "#,
+            &ctx,
+            json!({
+                "items": [
+                    {"id": "first"},
+                    {"id": "a", "title": "A"},
+                    {"id": "b", "title": "Bee"},
+                    {"id": "z", "title": "Zed"},
+                ]
+            }),
+        );
+
+        t(
+            r#"
+                -- $foo
+                -- @children[] ~text
+                a
+                -- @children[] ~text
+                b
+                -- @children[+1] ~text
+                between
+                -- @children[0] $bar
+                -- @children[0]/bar ~text
+                replaced
+            "#,
+            &ctx,
+            json!({
+                "hello": "world",
+                "main": "yo",
+                "obj": {
+                    "list": [{"bar": "replaced"}, "between", "b"]
+                },
+                "main2": "yo2",
+            }),
+        );
+
         /*t(
             r#"
                 -- $array2
@@ -951,4 +1638,493 @@ mod tests {
 
         */
     }
+
+    #[test]
+    fn validate() {
+        let ctx = StaticContext::new(
+            "card.json",
+            r#"{
+                "title": {
+                    "$ref": "title"
+                },
+                "tags": {
+                    "$ref": "tags[]"
+                }
+            }"#,
+        ).with(
+            "card.schema.json",
+            r#"{
+                "properties": {
+                    "title": {"type": "string"},
+                    "tags": {"type": "array", "items": {"type": "string"}}
+                }
+            }"#,
+        );
+
+        let txt = d(r#"
+            -- $card
+            title: hello
+            -- @tags[] ~text
+            yo
+        "#.trim_right());
+        assert_eq!(
+            super::convert_validated(&txt, &ctx).unwrap(),
+            json!({"title": "hello", "tags": ["yo"]})
+        );
+
+        let txt = d(r#"
+            -- $card
+            title: 10
+            -- @tags[]
+            yo: man
+        "#.trim_right());
+        assert!(super::convert(&txt, &ctx).is_ok());
+        assert_eq!(
+            super::convert_validated(&txt, &ctx).unwrap_err().to_string(),
+            "section `-- $card` (line 2) does not match card.schema.json:\n  \
+             /tags/0: expected type \"string\", found {\"yo\":\"man\"}\n  \
+             /title: expected type \"string\", found 10"
+        );
+    }
+
+    #[test]
+    fn holes() {
+        let ctx = StaticContext::new(
+            "post.json",
+            r#"{
+                "title": {
+                    "$ref": "title",
+                    "required": true,
+                    "message": "every post needs a title"
+                },
+                "slug": {
+                    "$ref": "slug",
+                    "default_ref": "title",
+                    "default": null,
+                    "transform": "slug"
+                },
+                "heading": {
+                    "$ref": "title",
+                    "default": "",
+                    "transform": "upper"
+                },
+                "year": {
+                    "$ref": "date",
+                    "transform": "date:%Y",
+                    "default": null
+                }
+            }"#,
+        );
+
+        t(
+            r#"
+                -- $post
+                title: Hello, World!
+                date: 2018-11-05
+            "#,
+            &ctx,
+            json!({
+                "title": "Hello, World!",
+                "slug": "hello-world",
+                "heading": "HELLO, WORLD!",
+                "year": "2018",
+            }),
+        );
+        t(
+            r#"
+                -- $post
+                title: Hello
+                slug: custom
+            "#,
+            &ctx,
+            json!({
+                "title": "Hello",
+                "slug": "custom",
+                "heading": "HELLO",
+                "year": null,
+            }),
+        );
+
+        let txt = d(r#"
+            -- $post
+            date: 2018-11-05"#);
+        assert_eq!(
+            super::convert(&txt, &ctx).unwrap_err().to_string(),
+            "section `-- $post` (line 2): every post needs a title"
+        );
+        let txt = d(r#"
+            -- $post
+            title: Hello
+            date: someday"#);
+        assert_eq!(
+            super::convert(&txt, &ctx).unwrap_err().to_string(),
+            "section `-- $post` (line 2): 'date': not a date: someday"
+        );
+    }
+
+    #[test]
+    fn anchors() {
+        let ctx = StaticContext::new(
+            "page.json",
+            r##"{
+                "body": {
+                    "$ref": "body[]"
+                },
+                "toc": {
+                    "$ref": "toc[]"
+                },
+                "lead": {
+                    "$ref": "#intro"
+                }
+            }"##,
+        );
+
+        t(
+            r#"
+                -- $page
+                -- @body[] #intro ~text
+                welcome
+                -- @body[]
+                id: rest
+                -- @toc[] =intro
+                -- @toc[] ~text
+                the end
+            "#,
+            &ctx,
+            json!({
+                "body": ["welcome", {"id": "rest"}],
+                "toc": ["welcome", "the end"],
+                "lead": "welcome",
+            }),
+        );
+
+        let txt = d(r#"
+            -- $page
+            -- @body[] =missing"#);
+        assert_eq!(
+            super::convert(&txt, &ctx).unwrap_err().to_string(),
+            "unknown id: #missing"
+        );
+
+        let txt = d(r#"
+            -- $page
+            -- @body[] #intro
+            -- @body[] #intro"#);
+        assert_eq!(
+            super::convert(&txt, &ctx).unwrap_err().to_string(),
+            "duplicate id #intro: lines 3 and 4"
+        );
+
+        let txt = d(r#"
+            -- $page
+            -- @body[] #intro $page
+            -- @body[]/toc[] =intro"#);
+        assert_eq!(
+            super::convert(&txt, &ctx).unwrap_err().to_string(),
+            "reference cycle: #intro -> #intro"
+        );
+
+        let txt = d(r#"
+            -- $page
+            -- @body[] #intro ~text if:env.a
+            hello
+            -- @body[] #intro ~text unless:env.a
+            welcome"#);
+        assert_eq!(
+            super::convert(&txt, &ctx).unwrap()["lead"],
+            json!("welcome")
+        );
+
+        let ctx = ctx.with("intro.graft", "-- @body[] #intro ~text\nwelcome");
+        let txt = d(r#"
+            -- $page
+            -- $intro
+            -- $intro"#);
+        assert_eq!(
+            super::convert(&txt, &ctx).unwrap_err().to_string(),
+            "duplicate id #intro: intro.graft line 1 (included at line 3) and \
+             intro.graft line 1 (included at line 4)"
+        );
+    }
+
+    #[test]
+    fn toc() {
+        let ctx = StaticContext::new(
+            "article.json",
+            r#"{
+                "toc": {
+                    "$ref": "@toc"
+                },
+                "body": {
+                    "$ref": "body[]"
+                }
+            }"#,
+        );
+
+        t(
+            r#"
+                -- $article
+                -- @body[] ~md
+                # Intro
+                hello
+                -- @body[] ~md
+                ## Setup & *usage*
+                ## Intro
+                # Next
+            "#,
+            &ctx,
+            json!({
+                "toc": [
+                    {
+                        "id": "intro",
+                        "title": "Intro",
+                        "level": 1,
+                        "children": [
+                            {
+                                "id": "setup-usage",
+                                "title": "Setup & usage",
+                                "level": 2,
+                                "children": [],
+                            },
+                            {
+                                "id": "intro-1",
+                                "title": "Intro",
+                                "level": 2,
+                                "children": [],
+                            },
+                        ],
+                    },
+                    {
+                        "id": "next",
+                        "title": "Next",
+                        "level": 1,
+                        "children": [],
+                    },
+                ],
+                "body": [
+                    "<h1 id=\"intro\">Intro</h1>\n<p>hello</p>\n",
+                    "<h2 id=\"setup-usage\">Setup &amp; <em>usage</em></h2>\n\
+                     <h2 id=\"intro-1\">Intro</h2>\n\
+                     <h1 id=\"next\">Next</h1>\n",
+                ],
+            }),
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let ctx = StaticContext::new(
+            "page.json",
+            r#"{
+                "title": {
+                    "$ref": "title"
+                },
+                "body": {
+                    "$ref": "body[]"
+                }
+            }"#,
+        );
+        let txt = d(r#"
+            -- $page
+            -- @title ~text
+            first
+            -- @body[]
+            -- @body[]/title ~text
+            item title
+            -- @title ~text
+            second
+            -- @footer"#);
+
+        let (value, diagnostics) =
+            super::convert_with_diagnostics(&txt, &ctx, &Params::new()).unwrap();
+        assert_eq!(value, json!({"title": "first", "body": [{}]}));
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "section `-- @body[]/title ~text` (line 6): @body[]/title is never used",
+                "section `-- @title ~text` (line 8): @title is shadowed by line 3",
+                "section `-- @footer` (line 10): @footer is never used",
+            ]
+        );
+
+        let ctx = ctx.with("title.graft", "-- @title ~text\nthird");
+        let txt = d(r#"
+            -- $page
+            -- @title ~text
+            first
+            -- @title ~text
+            second
+            -- $title"#);
+        let (_, diagnostics) =
+            super::convert_with_diagnostics(&txt, &ctx, &Params::new()).unwrap();
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "section `-- @title ~text` (line 5): @title is shadowed by line 3",
+                "section `-- @title ~text` (title.graft line 1): @title is shadowed by line 3",
+            ]
+        );
+        assert_eq!(diagnostics[1].file, Some("title.graft".into()));
+    }
+
+    #[test]
+    fn provenance() {
+        let ctx = StaticContext::new(
+            "page.json",
+            r#"{
+                "title": {
+                    "$ref": "title",
+                    "default": "untitled"
+                },
+                "main": {
+                    "$ref": "main"
+                }
+            }"#,
+        ).with("bar.json", r#"{"bar": {"$ref": "bar", "default": [1]}, "kind": "bar"}"#)
+        .with("layout.graft", "-- $page\n-- @main $bar\n");
+        let txt = d(r#"
+            -- $layout
+            -- @main/bar[+0]
+            0"#);
+
+        let (value, provenance) =
+            super::convert_with_provenance(&txt, &ctx, &Params::new()).unwrap();
+        assert_eq!(
+            value,
+            json!({"title": "untitled", "main": {"bar": [0, 1], "kind": "bar"}})
+        );
+        assert_eq!(
+            provenance
+                .iter()
+                .map(|(pointer, origin)| format!("{}: {}", pointer, origin))
+                .collect::<Vec<_>>(),
+            vec![
+                ": section `-- $page` (layout.graft line 1), from page.json",
+                "/main: section `-- @main $bar` (layout.graft line 2), from bar.json",
+                "/main/bar: section `-- @main $bar` (layout.graft line 2), default in bar.json",
+                "/main/bar/0: section `-- @main/bar[+0]` (line 3)",
+                "/main/bar/1: section `-- @main $bar` (layout.graft line 2), default in bar.json",
+                "/main/kind: section `-- @main $bar` (layout.graft line 2), from bar.json",
+                "/title: section `-- $page` (layout.graft line 1), default in page.json",
+            ]
+        );
+    }
+
+    #[test]
+    fn sourcemap() {
+        let page =
+            r#"{"title": {"$ref": "title", "default": "untitled"}, "tags": {"$ref": "tags"}}"#;
+        let ctx = StaticContext::new("page.json", page).with("list.yml", "- x");
+        let txt = "-- $page\n-- @tags ~json\n[\"a\", {\"b\": 1}]\n-- @tags[1]/c $list\n";
+
+        let (_, map) = super::convert_with_sourcemap(txt, &ctx, &Params::new()).unwrap();
+        let at = |pointer: &str| {
+            let mapping = &map.mappings[pointer];
+            let source = match mapping.file {
+                Some(ref file) => ctx.aliases[file].as_str(),
+                None => txt,
+            };
+            &source[mapping.span.start..mapping.span.end]
+        };
+        assert_eq!(at(""), page);
+        assert_eq!(at("/title"), r#""untitled""#);
+        assert_eq!(at("/tags"), r#"["a", {"b": 1}]"#);
+        assert_eq!(at("/tags/1/b"), "1");
+        assert_eq!(at("/tags/1/c/0"), "x");
+        assert_eq!(map.mappings["/tags/1/c/0"].file, Some("list.yml".into()));
+        assert_eq!(
+            map.to_json()["mappings"]["/tags/0"],
+            json!({"file": null, "start": 25, "end": 28})
+        );
+
+        let txt = "-- $page\n-- @tags\n- a\n- b: 1  # one\n";
+        let (_, map) = super::convert_with_sourcemap(txt, &ctx, &Params::new()).unwrap();
+        let span = map.mappings["/tags/1/b"].span;
+        assert_eq!(&txt[span.start..span.end], "1");
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn order() {
+        let ctx = StaticContext::new("base.json", r#"{"z": 1, "a": {"$ref": "a"}}"#).with(
+            "page.json",
+            r#"{"$extends": "base", "title": {"$ref": "title"}, "menu": {"$ref": "menu"}, "b": 2}"#,
+        );
+        let txt = d(r#"
+            -- $page
+            title: hello
+            -- @menu
+            zoo: /zoo
+            about: /about
+            home: /
+            -- @a ~json
+            {"y": 1, "x": 2}"#);
+
+        let value = super::convert(&txt, &ctx).unwrap();
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"z":1,"a":{"y":1,"x":2},"title":"hello","#.to_string()
+                + r#""menu":{"zoo":"/zoo","about":"/about","home":"/"},"b":2}"#
+        );
+    }
+
+    #[test]
+    fn convert_into() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Page {
+            title: String,
+            main: Main,
+        }
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Main {
+            count: u32,
+        }
+
+        let ctx = StaticContext::new(
+            "page.json",
+            r#"{
+                "title": {
+                    "$ref": "title",
+                    "default": "untitled"
+                },
+                "main": {
+                    "$ref": "main"
+                }
+            }"#,
+        );
+
+        let txt = d(r#"
+            -- $page
+            -- @main
+            count: 10
+        "#.trim_right());
+        assert_eq!(
+            super::convert_into::<Page, _>(&txt, &ctx).unwrap(),
+            Page {
+                title: "untitled".into(),
+                main: Main { count: 10 },
+            }
+        );
+
+        let txt = d(r#"
+            -- $page
+            title: hello
+            -- @main
+            count: ten
+        "#.trim_right());
+        assert_eq!(
+            super::convert_into::<Page, _>(&txt, &ctx)
+                .unwrap_err()
+                .to_string(),
+            "section `-- @main` (line 4): /main/count: \
+             invalid type: string \"ten\", expected u32"
+        );
+    }
 }
@@ -0,0 +1,224 @@
+use crate::context::Context;
+use crate::section::{load, Template};
+use failure::{err_msg, Error};
+use serde_json;
+use serde_yaml;
+
+/// Turns a json tree back into graft source.
+///
+/// Subtrees that match the shape of one of `templates` (tried in order) are
+/// emitted as `-- @path $template` sections carrying only the values that
+/// differ from the template defaults. Everything else is written out as
+/// `~text` or `~json` sections.
+pub fn decompile(
+    value: &serde_json::Value,
+    templates: &[&str],
+    ctx: &Context,
+) -> Result<String, Error> {
+    let mut loaded = vec![];
+    for name in templates {
+        match load(name, ctx)? {
+            Some(Template::Value(body)) => loaded.push((name.to_string(), body)),
+            Some(Template::Graft(_)) => {
+                return Err(err_msg(format!("can not decompile to graft template: {}", name)))
+            }
+            None => return Err(err_msg(format!("template not found: {}", name))),
+        }
+    }
+
+    let mut out = String::new();
+    emit("ROOT", value, &loaded, &mut out)?;
+    Ok(out)
+}
+
+struct Hole<'a> {
+    reference: &'a str,
+    default: Option<&'a serde_json::Value>,
+    value: &'a serde_json::Value,
+}
+
+fn emit(
+    reference: &str,
+    value: &serde_json::Value,
+    templates: &[(String, serde_json::Value)],
+    out: &mut String,
+) -> Result<(), Error> {
+    for (name, body) in templates {
+        let mut holes = vec![];
+        if !shape(body, value, &mut holes) {
+            continue;
+        }
+
+        if reference == "ROOT" {
+            out.push_str(&format!("-- ${}\n", name));
+        } else {
+            out.push_str(&format!("-- @{} ${}\n", reference, name));
+        }
+        let prefix = if reference == "ROOT" {
+            "".to_string()
+        } else {
+            reference.to_string() + "/"
+        };
+
+        let mut inline = serde_json::Map::new();
+        let mut nested = vec![];
+        for hole in holes {
+            if hole.reference.ends_with("[]") {
+                let items = hole.value.as_array().unwrap(); // safe because shape() checked it
+                for item in items {
+                    nested.push((prefix.clone() + hole.reference, item));
+                }
+                continue;
+            }
+            if hole.default == Some(hole.value) {
+                continue;
+            }
+            if templates.iter().any(|(_, t)| shape(t, hole.value, &mut vec![])) {
+                nested.push((prefix.clone() + hole.reference, hole.value));
+                continue;
+            }
+            inline.insert(hole.reference.to_string(), hole.value.clone());
+        }
+
+        if !inline.is_empty() {
+            let yaml = serde_yaml::to_string(&serde_json::Value::Object(inline))?;
+            out.push_str(&escape(yaml.trim_start_matches("---").trim()));
+            out.push('\n');
+        }
+        for (reference, value) in nested {
+            emit(&reference, value, templates, out)?;
+        }
+        return Ok(());
+    }
+
+    match value {
+        serde_json::Value::String(s) if !s.is_empty() && s.trim() == s => {
+            out.push_str(&format!("-- @{} ~text\n", reference));
+            out.push_str(&escape(s));
+        }
+        _ => {
+            out.push_str(&format!("-- @{} ~json\n", reference));
+            out.push_str(&escape(&serde_json::to_string_pretty(value)?));
+        }
+    }
+    out.push('\n');
+    Ok(())
+}
+
+/// Checks if `value` could have been produced by the template `tpl`, and
+/// collects the value that ended up in each `$ref` hole.
+fn shape<'a>(
+    tpl: &'a serde_json::Value,
+    value: &'a serde_json::Value,
+    holes: &mut Vec<Hole<'a>>,
+) -> bool {
+    match (tpl, value) {
+        (serde_json::Value::Object(t), _) if t.contains_key("$ref") => {
+            let reference = match t.get("$ref").and_then(|r| r.as_str()) {
+                Some(r) => r,
+                None => return false,
+            };
+            // absolute references are resolved outside of the subtree
+            if reference.starts_with('/') {
+                return false;
+            }
+            if reference.ends_with("[]") && !value.is_array() {
+                return false;
+            }
+            if let Some(hole) = holes.iter().find(|h| h.reference == reference) {
+                return hole.value == value;
+            }
+            holes.push(Hole {
+                reference,
+                default: t.get("default"),
+                value,
+            });
+            true
+        }
+        (serde_json::Value::Object(t), serde_json::Value::Object(v)) => {
+            t.len() == v.len()
+                && t
+                    .iter()
+                    .all(|(k, tv)| v.get(k).map_or(false, |vv| shape(tv, vv, holes)))
+        }
+        (serde_json::Value::Array(t), serde_json::Value::Array(v)) => {
+            t.len() == v.len() && t.iter().zip(v).all(|(tv, vv)| shape(tv, vv, holes))
+        }
+        _ => tpl == value,
+    }
+}
+
+/// Escapes body text so `Section::parse` reads it back verbatim.
+fn escape(body: &str) -> String {
+    body.replace("--", "/--")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::StaticContext;
+    use crate::convert::convert;
+    use textwrap::dedent as d;
+
+    #[test]
+    fn decompile() {
+        let ctx = StaticContext::new(
+            "foo.json",
+            r#"
+            {
+                "hello": "world",
+                "main": {
+                    "$ref": "main",
+                    "default": "yo"
+                },
+                "obj": {
+                    "list": {
+                        "$ref": "children[]"
+                    }
+                }
+            }
+            "#,
+        ).with(
+            "bar.json",
+            r#"{
+                "bar": {
+                    "$ref": "bar"
+                }
+            }"#,
+        );
+
+        let txt = super::decompile(
+            &json!({"hello": "world", "main": "hello main", "obj": {"list": []}}),
+            &["foo", "bar"],
+            &ctx,
+        ).unwrap();
+        assert_eq!(txt, "-- $foo\nmain: hello main\n");
+
+        let txt = super::decompile(&json!({"a": 1}), &["foo", "bar"], &ctx).unwrap();
+        assert_eq!(txt, "-- @ROOT ~json\n{\n  \"a\": 1\n}\n");
+
+        for src in &[
+            r#"
+                -- $foo
+                -- @main $bar
+                -- @main/bar
+                x: 20
+                y: "--"
+            "#,
+            r#"
+                -- $foo
+                -- @children[] ~text
+                child 1
+                -- @children[] $foo
+                main: nested
+                -- @children[]/children[] $bar
+                bar: [1, 2]
+                -- @children[] ~md
+                /-- not a header
+            "#,
+        ] {
+            let value = convert(&d(src.trim_right()), &ctx).unwrap();
+            let txt = super::decompile(&value, &["foo", "bar"], &ctx).unwrap();
+            assert_eq!(convert(&txt, &ctx).unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use crate::ast::{parse_document, Section, TokenKind};
+use crate::section::{Exec, Format};
+use failure::{err_msg, Error};
+use serde_json;
+use serde_yaml;
+
+/// Rewrites a document in canonical form:
+///
+/// - header tokens in the order `@ref $template ~format !process +mode #id
+///   =id if: unless:`, with format and process names normalized, like
+///   `~yml` to `~yaml`,
+/// - one blank line between sections and none at the start or end of
+///   bodies,
+/// - yaml and json bodies re-indented with two spaces, keeping key order
+///   and comments.
+///
+/// Other bodies and the text before the first section are kept as they are.
+/// Formatting a formatted document does not change it.
+pub fn format_document(txt: &str) -> Result<String, Error> {
+    let doc = parse_document(txt)?;
+
+    let mut out = trim_blank_lines(&doc.preamble);
+    if !out.is_empty() {
+        out += "\n\n";
+    }
+    for (i, section) in doc.sections.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out += &header(section);
+        out.push('\n');
+        let body = body(section)?;
+        if !body.is_empty() {
+            out += &body;
+            out.push('\n');
+        }
+    }
+
+    // formatting must not change what the document means
+    let formatted = parse_document(&out)?;
+    for (before, after) in doc.sections.iter().zip(&formatted.sections) {
+        if header(before) != header(after) || before.value != after.value {
+            return Err(err_msg(format!(
+                "line {}: formatting would change the section",
+                before.line
+            )));
+        }
+    }
+    if doc.sections.len() != formatted.sections.len() {
+        return Err(err_msg("formatting would change the sections"));
+    }
+    Ok(out)
+}
+
+fn header(section: &Section) -> String {
+    let mut tokens: Vec<(usize, String)> = section
+        .tokens
+        .iter()
+        .map(|t| {
+            let (rank, text) = match t.kind {
+                TokenKind::Reference => (0, t.text.clone()),
+                TokenKind::Include => (1, t.text.clone()),
+                TokenKind::Format => (2, format_name(&t.text)),
+                TokenKind::Process => (3, process_name(&t.text)),
+                TokenKind::Mode => (4, t.text.to_lowercase()),
+                TokenKind::Id => (5, t.text.clone()),
+                TokenKind::Alias => (6, t.text.clone()),
+                TokenKind::If => (7, t.text.clone()),
+                TokenKind::Unless => (8, t.text.clone()),
+                TokenKind::Unknown => (9, t.text.clone()),
+            };
+            (rank, text)
+        })
+        .collect();
+    tokens.sort_by_key(|(rank, _)| *rank);
+
+    let mut header = "--".to_string();
+    for (_, text) in tokens {
+        header.push(' ');
+        header += &text;
+    }
+    header
+}
+
+fn format_name(token: &str) -> String {
+    match Format::from_token(token) {
+        Ok(Format::Text) => "~text".into(),
+        Ok(Format::Markdown) => "~md".into(),
+        Ok(Format::YAML) => "~yaml".into(),
+        Ok(Format::JSON) => "~json".into(),
+        Ok(Format::Table) => "~table".into(),
+        Err(_) => token.into(),
+    }
+}
+
+fn process_name(token: &str) -> String {
+    match Exec::from_token(token) {
+        Ok(Exec::SQL) => "!sql".into(),
+        Ok(Exec::Shell) => "!sh".into(),
+        Ok(Exec::Python) => "!py".into(),
+        Err(_) => token.into(),
+    }
+}
+
+fn body(section: &Section) -> Result<String, Error> {
+    let body = trim_blank_lines(&section.body);
+    let formatted = match section.format()? {
+        Format::YAML => reindent_yaml(&body),
+        Format::JSON => reindent_json(&body),
+        _ => None,
+    };
+    match formatted {
+        // a line starting with `--` would become a header
+        Some(f) if !f.starts_with("--") && !f.contains("\n--") => Ok(f),
+        _ => Ok(body),
+    }
+}
+
+/// Drops whitespace only lines at the start and end of `txt`, and the
+/// final newline.
+fn trim_blank_lines(txt: &str) -> String {
+    let lines: Vec<&str> = txt.lines().collect();
+    let first = lines.iter().position(|l| !l.trim().is_empty());
+    let last = lines.iter().rposition(|l| !l.trim().is_empty());
+    match (first, last) {
+        (Some(first), Some(last)) => lines[first..=last].join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Indents each nesting level with two spaces, and lists under a key one
+/// level deeper than the key. Block scalars keep the indentation of their
+/// lines relative to each other. Returns `None` if that would change the
+/// value.
+fn reindent_yaml(body: &str) -> Option<String> {
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let lines: Vec<&str> = body.lines().collect();
+    let mut out = vec![String::new(); lines.len()];
+    let mut comments = vec![];
+    // indent and level of the lines the next one may be nested in, and
+    // whether the entry is for a list written at the indent of its key
+    let mut stack: Vec<(usize, usize, bool)> = vec![];
+    // indent of the line starting a block scalar, the level of its content
+    // and the indent of its first line
+    let mut scalar: Option<(usize, usize, Option<usize>)> = None;
+    let mut opens = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        let n = indent(line);
+        if let Some((parent, level, first)) = scalar {
+            if trimmed.is_empty() {
+                continue;
+            }
+            if n > parent {
+                let first = first.unwrap_or(n);
+                scalar = Some((parent, level, Some(first)));
+                out[i] = "  ".repeat(level) + line[first.min(n)..].trim_end();
+                continue;
+            }
+            scalar = None;
+        }
+        if trimmed.is_empty() {
+            continue;
+        }
+        if trimmed.starts_with('#') {
+            comments.push(i);
+            continue;
+        }
+
+        let item = trimmed == "-" || trimmed.starts_with("- ");
+        while let Some(&(top, _, list)) = stack.last() {
+            if top > n || (top == n && list && !item) {
+                stack.pop();
+            } else {
+                break;
+            }
+        }
+        let level = match stack.last() {
+            Some(&(top, level, list)) if top == n => {
+                if item && !list && opens {
+                    stack.push((n, level + 1, true));
+                    level + 1
+                } else {
+                    level
+                }
+            }
+            Some(&(_, level, _)) => {
+                stack.push((n, level + 1, false));
+                level + 1
+            }
+            None => {
+                stack.push((n, 0, false));
+                0
+            }
+        };
+        out[i] = "  ".repeat(level) + trimmed;
+        for c in comments.drain(..) {
+            out[c] = "  ".repeat(level) + lines[c].trim();
+        }
+
+        opens = trimmed.ends_with(':');
+        let last = trimmed.rsplit(' ').next().unwrap_or("");
+        if (last.starts_with('|') || last.starts_with('>'))
+            && last[1..].chars().all(|c| "+-0123456789".contains(c))
+        {
+            scalar = Some((n, level + 1, None));
+        }
+    }
+    for c in comments {
+        out[c] = lines[c].trim().to_string();
+    }
+    let out = out.join("\n");
+
+    let before: serde_yaml::Value = serde_yaml::from_str(body).ok()?;
+    let after: serde_yaml::Value = serde_yaml::from_str(&out).ok()?;
+    if before == after {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Pretty prints json with two spaces, keeping the order of keys.
+fn reindent_json(body: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut depth = 0;
+    let mut chars = body.chars().peekable();
+    let newline = |out: &mut String, depth: usize| {
+        out.push('\n');
+        out.push_str(&"  ".repeat(depth));
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                out.push(c);
+                while let Some(c) = chars.next() {
+                    out.push(c);
+                    match c {
+                        '\\' => out.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '{' | '[' => {
+                out.push(c);
+                while chars.peek().map_or(false, |c| c.is_whitespace()) {
+                    chars.next();
+                }
+                match chars.peek() {
+                    Some('}') | Some(']') => out.extend(chars.next()),
+                    _ => {
+                        depth += 1;
+                        newline(&mut out, depth);
+                    }
+                }
+            }
+            '}' | ']' => {
+                depth = depth.checked_sub(1)?;
+                newline(&mut out, depth);
+                out.push(c);
+            }
+            ',' => {
+                out.push(c);
+                newline(&mut out, depth);
+            }
+            ':' => out.push_str(": "),
+            c if c.is_whitespace() => {}
+            c => out.push(c),
+        }
+    }
+
+    let before: serde_json::Value = serde_json::from_str(body).ok()?;
+    let after: serde_json::Value = serde_json::from_str(&out).ok()?;
+    if before == after {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_document;
+
+    #[test]
+    fn format() {
+        let txt = [
+            "a note",
+            "-- $page   @main ~YML",
+            "title: hello   # the title",
+            "tags:",
+            "    - a",
+            "    - b",
+            "",
+            "",
+            "--   ~markdown @body",
+            "some *text*  ",
+            "",
+            "-- ~json @data",
+            r#"{"b": [1,2, {}], "a": {"x": "y, z: {"}}"#,
+            "--  @code ~text",
+            "    indented /-- text",
+        ]
+        .join("\n");
+
+        let formatted = format_document(&txt).unwrap();
+        assert_eq!(
+            formatted,
+            [
+                "a note",
+                "",
+                "-- @main $page ~yaml",
+                "title: hello   # the title",
+                "tags:",
+                "  - a",
+                "  - b",
+                "",
+                "-- @body ~md",
+                "some *text*  ",
+                "",
+                "-- @data ~json",
+                "{",
+                r#"  "b": ["#,
+                "    1,",
+                "    2,",
+                "    {}",
+                "  ],",
+                r#"  "a": {"#,
+                r#"    "x": "y, z: {""#,
+                "  }",
+                "}",
+                "",
+                "-- @code ~text",
+                "    indented /-- text",
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(format_document(&formatted).unwrap(), formatted);
+
+        let canonical = [
+            "-- @data",
+            "a:",
+            "  b:",
+            "    c: 1",
+            "  # the list",
+            "  list:",
+            "    - x: 1",
+            "      y: 2",
+            "d:",
+            "  e: |",
+            "    one",
+            "      two",
+            "",
+        ]
+        .join("\n");
+        let txt = [
+            "-- @data",
+            "a:",
+            "    b:",
+            "            c: 1",
+            "# the list",
+            "    list:",
+            "    - x: 1",
+            "      y: 2",
+            "d:",
+            "   e: |",
+            "       one",
+            "         two",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(format_document(&txt).unwrap(), canonical);
+        assert_eq!(format_document(&canonical).unwrap(), canonical);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::section::Section;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Which sections end a scan for a path: sections whose reference is a
+/// parent of it (`a` and `a/b` for `a/b/c`), or any prefix of it at all.
+#[derive(Clone, Copy)]
+pub enum Ancestors {
+    Parents,
+    Prefixes,
+}
+
+/// The positions of the sections for each reference, built once so that
+/// resolving a `$ref` does not scan every section.
+pub struct Index<'a> {
+    refs: BTreeMap<&'a str, Vec<usize>>,
+    len: usize,
+}
+
+impl<'a> Index<'a> {
+    pub fn new(sections: &'a [Section]) -> Index<'a> {
+        let mut refs: BTreeMap<&'a str, Vec<usize>> = BTreeMap::new();
+        for (idx, section) in sections.iter().enumerate() {
+            refs.entry(&section.reference)
+                .or_insert_with(Vec::new)
+                .push(idx);
+        }
+        Index {
+            refs,
+            len: sections.len(),
+        }
+    }
+
+    /// Positions at or after `start` and before `limit` of the sections for
+    /// `reference`.
+    pub fn range(&self, reference: &str, start: usize, limit: usize) -> &[usize] {
+        match self.refs.get(reference) {
+            Some(positions) => {
+                let from = lower_bound(positions, start);
+                let to = lower_bound(positions, limit).max(from);
+                &positions[from..to]
+            }
+            None => &[],
+        }
+    }
+
+    /// Position of the first section for `reference` at or after `start`.
+    pub fn first(&self, reference: &str, start: usize) -> Option<usize> {
+        self.range(reference, start, self.len).first().cloned()
+    }
+
+    /// Position of the first section for `till`, where all scans stop.
+    pub fn end(&self, till: &str) -> usize {
+        self.first(till, 0).unwrap_or(self.len)
+    }
+
+    /// Where a scan for `path` starting at `start` stops: at the `end`, or
+    /// the first section after `start` for one of its `ancestors`.
+    pub fn limit(&self, path: &str, start: usize, till: &str, ancestors: Ancestors) -> usize {
+        let mut limit = self.end(till);
+        for (i, c) in path.char_indices() {
+            let prefix = match ancestors {
+                Ancestors::Parents if c == '/' => &path[..i],
+                Ancestors::Prefixes => &path[..i],
+                _ => continue,
+            };
+            if let Some(idx) = self.first(prefix, start) {
+                limit = limit.min(idx);
+            }
+        }
+        limit
+    }
+
+    /// Positions at or after `start` and before `limit` of the sections
+    /// whose reference starts with `prefix`, in order, with their reference.
+    pub fn prefixed(&self, prefix: &str, start: usize, limit: usize) -> Vec<(usize, &'a str)> {
+        let mut found = vec![];
+        let range = (Bound::Included(prefix), Bound::Unbounded);
+        for (reference, _) in self.refs.range::<str, _>(range) {
+            if !reference.starts_with(prefix) {
+                break;
+            }
+            found.extend(
+                self.range(reference, start, limit)
+                    .iter()
+                    .map(|idx| (*idx, *reference)),
+            );
+        }
+        found.sort();
+        found
+    }
+}
+
+fn lower_bound(positions: &[usize], idx: usize) -> usize {
+    match positions.binary_search(&idx) {
+        Ok(i) | Err(i) => i,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ancestors, Index};
+    use crate::context::StaticContext;
+    use crate::section::Section;
+
+    #[test]
+    fn index() {
+        let ctx = StaticContext::new("", "");
+        let sections = Section::parse(
+            "-- @a[]\n-- @a[]/b\n-- @a[]\n-- @a[1]\n-- @x\n-- @a[]\n-- @END\n-- @a[]\n",
+            &ctx,
+        )
+        .unwrap();
+        let index = Index::new(&sections);
+
+        assert_eq!(index.range("a[]", 1, 8), &[2, 5, 7]);
+        assert_eq!(index.first("a[]/b", 2), None);
+        assert_eq!(index.limit("a[]/b", 0, "END", Ancestors::Parents), 0);
+        assert_eq!(index.limit("a[]/b", 1, "END", Ancestors::Parents), 2);
+        assert_eq!(index.limit("x/y", 0, "END", Ancestors::Parents), 4);
+        assert_eq!(index.limit("xy", 0, "END", Ancestors::Parents), 6);
+        assert_eq!(index.limit("xy", 0, "END", Ancestors::Prefixes), 4);
+        assert_eq!(
+            index.prefixed("a[", 0, 6),
+            vec![
+                (0, "a[]"),
+                (1, "a[]/b"),
+                (2, "a[]"),
+                (3, "a[1]"),
+                (5, "a[]")
+            ]
+        );
+    }
+}
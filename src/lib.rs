@@ -5,11 +5,44 @@ extern crate serde_json;
 #[cfg(test)]
 #[macro_use]
 extern crate pretty_assertions;
+#[cfg(test)]
+#[macro_use]
+extern crate serde_derive;
 
+mod ast;
+mod batch;
 mod context;
 mod convert;
+mod decompile;
+mod formatter;
+mod index;
+mod lint;
+mod lsp;
+mod output;
+mod parser;
+mod schema;
 mod section;
+mod sourcemap;
 mod table_format;
+mod toc;
+mod transform;
+mod vars;
+mod yaml;
 
+pub use crate::ast::{parse_document, tokenize, Document, Section, Span, Token, TokenKind};
+pub use crate::batch::{convert_dir, convert_documents, convert_file, Report};
 pub use crate::context::*;
-pub use crate::convert::convert;
+pub use crate::convert::{
+    convert, convert_into, convert_validated, convert_with, convert_with_diagnostics,
+    convert_with_provenance, convert_with_sourcemap, Diagnostic, Origin,
+};
+pub use crate::decompile::decompile;
+pub use crate::formatter::format_document;
+pub use crate::lint::{lint, Level, Lint, LintConfig, Rule};
+pub use crate::lsp::LanguageServer;
+pub use crate::output::{serialize, Output};
+pub use crate::parser::{scan, OwnedSection, RawSection, Reader, Scan};
+pub use crate::section::{Exec, Format};
+pub use crate::sourcemap::{Mapping, SourceMap};
+pub use crate::vars::Params;
+pub use crate::yaml::Anchors;
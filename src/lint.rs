@@ -0,0 +1,290 @@
+use crate::ast::parse_document;
+use crate::context::Context;
+use crate::convert::{sections, shadowing, usage};
+use crate::section::{Format, Section};
+use crate::vars::Params;
+use failure::{err_msg, Error};
+use serde_json;
+use serde_yaml;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// A section no `$ref` uses.
+    DeadContent,
+    /// A key in the body of an include that is not a hole in the template.
+    UnknownOverride,
+    /// A section below a list item that comes before any such item.
+    OutOfOrder,
+    /// A second section for a reference, which is ignored.
+    Duplicate,
+    /// A `~md` section whose body looks like yaml.
+    MarkdownLooksLikeYaml,
+}
+
+impl Rule {
+    pub fn all() -> Vec<Rule> {
+        vec![
+            Rule::DeadContent,
+            Rule::UnknownOverride,
+            Rule::OutOfOrder,
+            Rule::Duplicate,
+            Rule::MarkdownLooksLikeYaml,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::DeadContent => "dead-content",
+            Rule::UnknownOverride => "unknown-override",
+            Rule::OutOfOrder => "out-of-order",
+            Rule::Duplicate => "duplicate",
+            Rule::MarkdownLooksLikeYaml => "md-looks-like-yaml",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Rule, Error> {
+        Rule::all()
+            .into_iter()
+            .find(|r| r.name() == name)
+            .ok_or_else(|| err_msg(format!("unknown lint rule: {}", name)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// The level of each rule, `Warn` unless set otherwise.
+#[derive(Debug, Default, Clone)]
+pub struct LintConfig {
+    pub levels: HashMap<Rule, Level>,
+}
+
+impl LintConfig {
+    pub fn new() -> LintConfig {
+        LintConfig::default()
+    }
+
+    pub fn with(mut self, rule: Rule, level: Level) -> LintConfig {
+        self.levels.insert(rule, level);
+        self
+    }
+
+    pub fn level(&self, rule: Rule) -> Level {
+        self.levels.get(&rule).cloned().unwrap_or(Level::Warn)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Lint {
+    pub rule: Rule,
+    pub level: Level,
+    /// The `.graft` template holding the section, `None` for the document.
+    pub file: Option<String>,
+    /// Line of the section header, in `file` if any.
+    pub line: usize,
+    pub message: String,
+}
+
+/// Checks `txt` against the rules enabled in `config`. The document is
+/// converted with `params` to find out which sections are used, so it has
+/// to convert without errors.
+pub fn lint<T>(txt: &str, ctx: &T, params: &Params, config: &LintConfig) -> Result<Vec<Lint>, Error>
+where
+    T: Context,
+{
+    let (sections, toc) = sections(txt, ctx, params)?;
+    let used = usage(&sections, toc)?;
+    let mut lints = vec![];
+    let mut report = |rule: Rule, section: (&Option<String>, usize), message: String| {
+        let level = config.level(rule);
+        if level != Level::Allow {
+            lints.push(Lint {
+                rule,
+                level,
+                file: section.0.clone(),
+                line: section.1,
+                message,
+            });
+        }
+    };
+
+    for (idx, section) in sections.iter().enumerate() {
+        if used[idx] || section.reference == "END" {
+            continue;
+        }
+        let at = (&section.source, section.line);
+        if let Some(first) = shadowing(&sections, idx) {
+            let first = &sections[first];
+            let place = match first.source {
+                Some(ref file) => format!("{} line {}", file, first.line),
+                None => format!("line {}", first.line),
+            };
+            report(
+                Rule::Duplicate,
+                at,
+                format!(
+                    "@{} is already given on {}, this one is ignored",
+                    section.reference, place
+                ),
+            );
+        } else if section.inline {
+            report(
+                Rule::UnknownOverride,
+                at,
+                format!("`{}` is not a hole in the template", key(section)),
+            );
+        } else {
+            report(
+                Rule::DeadContent,
+                at,
+                format!("@{} is never used", section.reference),
+            );
+        }
+    }
+
+    for (idx, section) in sections.iter().enumerate() {
+        let parent = match section.reference.rfind('/') {
+            Some(i) if section.reference[..i].ends_with("[]") => &section.reference[..i],
+            _ => continue,
+        };
+        if !sections[..idx].iter().any(|s| s.reference == parent) {
+            report(
+                Rule::OutOfOrder,
+                (&section.source, section.line),
+                format!("@{} comes before any @{}", section.reference, parent),
+            );
+        }
+    }
+
+    for section in parse_document(txt)?.sections {
+        if section.format()? == Format::Markdown && looks_like_yaml(&section.body) {
+            report(
+                Rule::MarkdownLooksLikeYaml,
+                (&None, section.line),
+                "the body is rendered as markdown but looks like yaml".into(),
+            );
+        }
+    }
+
+    // the document first, then each template
+    lints.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    Ok(lints)
+}
+
+/// The key an inline section was given as in the body of its include.
+fn key(section: &Section) -> &str {
+    match section.reference.rfind('/') {
+        Some(i) => &section.reference[i + 1..],
+        None => &section.reference,
+    }
+}
+
+/// Whether a body is a yaml object with more than one key, or a key with a
+/// nested value, which prose hardly ever parses as.
+fn looks_like_yaml(body: &str) -> bool {
+    match serde_yaml::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(o)) => {
+            o.len() > 1 || o.values().any(|v| v.is_object() || v.is_array())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Level, Lint, LintConfig, Rule};
+    use crate::context::StaticContext;
+    use crate::vars::Params;
+    use textwrap::dedent as d;
+
+    #[test]
+    fn lint() {
+        let ctx = StaticContext::new(
+            "page.json",
+            r#"{
+                "title": {
+                    "$ref": "title",
+                    "default": ""
+                },
+                "body": {
+                    "$ref": "body[]"
+                }
+            }"#,
+        );
+        let txt = d(r#"
+            -- $page
+            titel: typo
+            -- @body[]/note
+            early: true
+            -- @title ~text
+            first
+            -- @title ~text
+            second
+            -- @body[] ~md
+            title: not markdown
+            tags: [a, b]
+            -- @footer
+            text: unused"#);
+
+        let lints = super::lint(&txt, &ctx, &Params::new(), &LintConfig::new()).unwrap();
+        let summary: Vec<(Rule, usize)> = lints.iter().map(|l| (l.rule, l.line)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Rule::UnknownOverride, 2),
+                (Rule::DeadContent, 4),
+                (Rule::OutOfOrder, 4),
+                (Rule::Duplicate, 8),
+                (Rule::MarkdownLooksLikeYaml, 10),
+                (Rule::DeadContent, 13),
+            ]
+        );
+        assert_eq!(
+            lints[3],
+            Lint {
+                rule: Rule::Duplicate,
+                level: Level::Warn,
+                file: None,
+                line: 8,
+                message: "@title is already given on line 6, this one is ignored".into(),
+            }
+        );
+
+        let config = LintConfig::new()
+            .with(Rule::DeadContent, Level::Allow)
+            .with(Rule::from_name("duplicate").unwrap(), Level::Deny);
+        let lints = super::lint(&txt, &ctx, &Params::new(), &config).unwrap();
+        assert!(lints.iter().all(|l| l.rule != Rule::DeadContent));
+        assert!(lints
+            .iter()
+            .any(|l| l.rule == Rule::Duplicate && l.level == Level::Deny));
+
+        let ctx = ctx.with("extra.graft", "-- @title ~text\nthird\n-- @footer\n");
+        let txt = d(r#"
+            -- $page
+            -- @title ~text
+            first
+            -- $extra"#);
+        let lints = super::lint(&txt, &ctx, &Params::new(), &LintConfig::new()).unwrap();
+        let summary: Vec<(Option<&str>, usize, &str)> = lints
+            .iter()
+            .map(|l| (l.file.as_ref().map(|f| f.as_str()), l.line, l.message.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    Some("extra.graft"),
+                    1,
+                    "@title is already given on line 3, this one is ignored"
+                ),
+                (Some("extra.graft"), 3, "@footer is never used"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,528 @@
+use crate::ast::parse_document;
+use crate::context::DirContext;
+use crate::convert::convert_with_diagnostics;
+use crate::section::{load, Template};
+use crate::vars::Params;
+use failure::{err_msg, Error};
+use serde_json;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const EXTENSIONS: &[&str] = &["json", "yml", "yaml", "txt", "graft"];
+
+/// A language server for graft files, speaking JSON-RPC over any reader
+/// and writer, usually stdin and stdout.
+///
+/// Templates are looked up in the directory of the edited file.
+#[derive(Default)]
+pub struct LanguageServer {
+    /// Text of the open documents, by uri.
+    documents: HashMap<String, String>,
+    exit: bool,
+}
+
+impl LanguageServer {
+    pub fn new() -> LanguageServer {
+        LanguageServer::default()
+    }
+
+    /// Serves requests until the client sends `exit` or closes the input.
+    /// A malformed message gets a parse error reply, and serving goes on.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut reader: R, mut writer: W) -> Result<(), Error> {
+        while !self.exit {
+            let replies = match read_message(&mut reader) {
+                Ok(Some(message)) => self.handle(&message),
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    // reading itself failed, nothing more will come
+                    if e.downcast_ref::<io::Error>().is_some() {
+                        return Err(e);
+                    }
+                    vec![json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": {"code": -32700, "message": e.to_string()},
+                    })]
+                }
+            };
+            for out in replies {
+                let body = serde_json::to_string(&out)?;
+                write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one request or notification, returning the messages to send.
+    pub fn handle(&mut self, message: &serde_json::Value) -> Vec<serde_json::Value> {
+        let id = message.get("id").cloned();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        match message["method"].as_str().unwrap_or("") {
+            "initialize" => reply(
+                id,
+                json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "completionProvider": {"triggerCharacters": ["$", "@"]},
+                        "hoverProvider": true,
+                        "definitionProvider": true,
+                    }
+                }),
+            ),
+            "shutdown" => reply(id, serde_json::Value::Null),
+            "exit" => {
+                self.exit = true;
+                vec![]
+            }
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                vec![self.diagnostics(&uri)]
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                vec![self.diagnostics(&uri)]
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![publish(&uri, vec![])]
+            }
+            "textDocument/completion" => reply(id, self.completion(&uri, params)),
+            "textDocument/hover" => reply(id, self.hover(&uri, params)),
+            "textDocument/definition" => reply(id, self.definition(&uri, params)),
+            method => match id {
+                Some(id) => vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": -32601, "message": format!("unknown method: {}", method)},
+                })],
+                None => vec![],
+            },
+        }
+    }
+
+    fn context(&self, uri: &str) -> DirContext {
+        let dir = path(uri)
+            .and_then(|p| p.parent().map(|d| d.to_path_buf()))
+            .unwrap_or_else(|| PathBuf::from("."));
+        DirContext::new(dir)
+    }
+
+    /// Errors from parsing and converting the document, and warnings for
+    /// the sections that were not used. Variables that are not defined in
+    /// the document are left alone, as they may be given with `-p` later.
+    fn diagnostics(&self, uri: &str) -> serde_json::Value {
+        let text = match self.documents.get(uri) {
+            Some(text) => text,
+            None => return publish(uri, vec![]),
+        };
+        let error = |e: Error| diagnostic(error_line(&e.to_string()), 1, e.to_string());
+
+        if let Err(e) = parse_document(text) {
+            return publish(uri, vec![error(e)]);
+        }
+        let params = Params::new().strict(false);
+        let diagnostics = match convert_with_diagnostics(text, &self.context(uri), &params) {
+            // sections of included templates are not in this file
+            Ok((_, unused)) => unused
+                .iter()
+                .filter(|d| d.file.is_none())
+                .map(|d| diagnostic(d.line, 2, d.to_string()))
+                .collect(),
+            Err(e) => vec![error(e)],
+        };
+        publish(uri, diagnostics)
+    }
+
+    /// Template names after `$`, and the holes of the included templates
+    /// after `@`.
+    fn completion(&self, uri: &str, params: &serde_json::Value) -> serde_json::Value {
+        let (before, _) = self.word(uri, params);
+        let ctx = self.context(uri);
+        let labels = if before.starts_with('$') {
+            ctx.templates()
+        } else if before.starts_with('@') {
+            self.references(uri, &ctx)
+        } else {
+            vec![]
+        };
+        let items: Vec<serde_json::Value> = labels
+            .into_iter()
+            .map(|label| json!({"label": label}))
+            .collect();
+        json!(items)
+    }
+
+    /// Paths the `$ref`s of the templates included in the document ask for.
+    fn references(&self, uri: &str, ctx: &DirContext) -> Vec<String> {
+        let doc = match self.documents.get(uri).map(|t| parse_document(t)) {
+            Some(Ok(doc)) => doc,
+            _ => return vec![],
+        };
+        let mut references = vec![];
+        for section in &doc.sections {
+            let include = match section.include() {
+                Some(include) => include,
+                None => continue,
+            };
+            let prefix = match section.reference() {
+                "ROOT" => "".to_string(),
+                r => r.to_string() + "/",
+            };
+            if let Ok(Some(Template::Value(v))) = load(include, ctx) {
+                let mut found = vec![];
+                holes(&v, &mut found);
+                references.extend(found.into_iter().map(|(r, _)| prefix.clone() + &r));
+            }
+        }
+        references.sort();
+        references.dedup();
+        references
+    }
+
+    /// The holes of a `$template` under the cursor, with their defaults.
+    fn hover(&self, uri: &str, params: &serde_json::Value) -> serde_json::Value {
+        let (before, after) = self.word(uri, params);
+        let word = before + &after;
+        if !word.starts_with('$') {
+            return serde_json::Value::Null;
+        }
+        let contents = match load(&word[1..], &self.context(uri)) {
+            Ok(Some(Template::Value(v))) => {
+                let mut found = vec![];
+                holes(&v, &mut found);
+                found.sort_by(|a, b| a.0.cmp(&b.0));
+                let mut contents = format!("**{}**\n", &word[1..]);
+                for (reference, default) in found {
+                    contents += &match default {
+                        Some(d) => format!("\n- `@{}`: defaults to `{}`", reference, d),
+                        None => format!("\n- `@{}`: required", reference),
+                    };
+                }
+                contents
+            }
+            Ok(Some(Template::Graft(txt))) => format!("**{}**\n\n```\n{}\n```", &word[1..], txt),
+            _ => return serde_json::Value::Null,
+        };
+        json!({"contents": {"kind": "markdown", "value": contents}})
+    }
+
+    /// The file of a `$template` under the cursor.
+    fn definition(&self, uri: &str, params: &serde_json::Value) -> serde_json::Value {
+        let (before, after) = self.word(uri, params);
+        let word = before + &after;
+        if !word.starts_with('$') {
+            return serde_json::Value::Null;
+        }
+        let dir = self.context(uri).dir;
+        for ext in EXTENSIONS {
+            let file = dir.join(format!("{}.{}", &word[1..], ext));
+            if file.is_file() {
+                return json!({
+                    "uri": file_uri(&file),
+                    "range": {
+                        "start": {"line": 0, "character": 0},
+                        "end": {"line": 0, "character": 0},
+                    },
+                });
+            }
+        }
+        serde_json::Value::Null
+    }
+
+    /// The parts of the whitespace separated word at the cursor before and
+    /// after it. The position counts utf-16 code units, as in the protocol.
+    fn word(&self, uri: &str, params: &serde_json::Value) -> (String, String) {
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+        let text = self
+            .documents
+            .get(uri)
+            .and_then(|t| t.lines().nth(line))
+            .unwrap_or("");
+        let chars: Vec<char> = text.chars().collect();
+        let mut units = 0;
+        let character = chars
+            .iter()
+            .position(|c| {
+                units += c.len_utf16();
+                units > character
+            })
+            .unwrap_or(chars.len());
+        let start = chars[..character]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |i| i + 1);
+        let end = chars[character..]
+            .iter()
+            .position(|c| c.is_whitespace())
+            .map_or(chars.len(), |i| character + i);
+        (
+            chars[start..character].iter().collect(),
+            chars[character..end].iter().collect(),
+        )
+    }
+}
+
+fn reply(id: Option<serde_json::Value>, result: serde_json::Value) -> Vec<serde_json::Value> {
+    vec![json!({"jsonrpc": "2.0", "id": id, "result": result})]
+}
+
+fn publish(uri: &str, diagnostics: Vec<serde_json::Value>) -> serde_json::Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+/// A diagnostic covering the whole of `line`, which is 1 based.
+fn diagnostic(line: usize, severity: u8, message: String) -> serde_json::Value {
+    let line = line.max(1) - 1;
+    json!({
+        "range": {
+            "start": {"line": line, "character": 0},
+            "end": {"line": line + 1, "character": 0},
+        },
+        "severity": severity,
+        "source": "graft",
+        "message": message,
+    })
+}
+
+/// The first `line N` in an error message, or 1.
+fn error_line(message: &str) -> usize {
+    message
+        .match_indices("line ")
+        .filter_map(|(i, _)| {
+            let digits: String = message[i + 5..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse().ok()
+        })
+        .next()
+        .unwrap_or(1)
+}
+
+/// Collects the `$ref`s in a template with their defaults.
+fn holes(value: &serde_json::Value, found: &mut Vec<(String, Option<serde_json::Value>)>) {
+    match value {
+        serde_json::Value::Object(o) => {
+            if let Some(serde_json::Value::String(r)) = o.get("$ref") {
+                found.push((r.to_string(), o.get("default").cloned()));
+                return;
+            }
+            for v in o.values() {
+                holes(v, found);
+            }
+        }
+        serde_json::Value::Array(a) => {
+            for v in a {
+                holes(v, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The path of a `file://` uri, percent-decoded.
+fn path(uri: &str) -> Option<PathBuf> {
+    if !uri.starts_with("file://") {
+        return None;
+    }
+    let encoded = uri["file://".len()..].as_bytes();
+    let mut bytes = vec![];
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(b) if encoded[i] == b'%' => {
+                bytes.push(b);
+                i += 3;
+            }
+            _ => {
+                bytes.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// The `file://` uri of `path`, percent-encoding all but the characters
+/// that are safe in a path.
+fn file_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for b in path.display().to_string().bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                uri.push(b as char)
+            }
+            b => uri += &format!("%{:02X}", b),
+        }
+    }
+    uri
+}
+
+fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<serde_json::Value>, Error> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        let lower = line.to_lowercase();
+        if lower.starts_with("content-length:") {
+            length = Some(line["content-length:".len()..].trim().parse::<usize>()?);
+        }
+    }
+    let length = length.ok_or_else(|| err_msg("message without Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LanguageServer;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn session() {
+        let dir = std::env::temp_dir().join(format!("graft-lsp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("page.json"),
+            r#"{"title": {"$ref": "title", "default": "untitled"}, "body": {"$ref": "body[]"}}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("page.schema.json"), "{}").unwrap();
+        let uri = format!("file://{}", dir.join("doc.graft").display());
+
+        let mut server = LanguageServer::new();
+        let open = |text: &str| {
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {"textDocument": {"uri": uri, "text": text}},
+            })
+        };
+        let at = |method: &str, line: usize, character: usize| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": {
+                    "textDocument": {"uri": uri},
+                    "position": {"line": line, "character": character},
+                },
+            })
+        };
+
+        let out = server.handle(&open("-- $page\n-- @footer\n-- @"));
+        let diagnostics = &out[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 2);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 1);
+        assert_eq!(
+            diagnostics[0]["message"],
+            "section `-- @footer` (line 2): @footer is never used"
+        );
+
+        let out = server.handle(&at("textDocument/completion", 2, 4));
+        assert_eq!(
+            out[0]["result"],
+            json!([{"label": "body[]"}, {"label": "title"}])
+        );
+        let out = server.handle(&at("textDocument/completion", 0, 4));
+        assert_eq!(out[0]["result"], json!([{"label": "page"}]));
+
+        let out = server.handle(&at("textDocument/hover", 0, 5));
+        assert_eq!(
+            out[0]["result"]["contents"]["value"],
+            "**page**\n\n- `@body[]`: required\n- `@title`: defaults to `\"untitled\"`"
+        );
+
+        let out = server.handle(&at("textDocument/definition", 0, 5));
+        assert_eq!(
+            out[0]["result"]["uri"],
+            format!("file://{}", dir.join("page.json").display())
+        );
+
+        let out = server.handle(&open("-- ~json\n{"));
+        assert_eq!(out[0]["params"]["diagnostics"][0]["severity"], 1);
+
+        let out = server.handle(&open("-- $page\ntitle: ${site.name}\n-- @body[]\n"));
+        assert_eq!(out[0]["params"]["diagnostics"], json!([]));
+
+        // the emoji take two utf-16 code units each
+        server.handle(&open("\u{1F600}\u{1F600} $page x"));
+        let out = server.handle(&at("textDocument/hover", 0, 10));
+        assert!(out[0]["result"]["contents"]["value"]
+            .as_str()
+            .unwrap()
+            .starts_with("**page**"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn run() {
+        let body = r#"{"jsonrpc": "2.0", "id": 7, "method": "shutdown"}"#;
+        let exit = r#"{"jsonrpc": "2.0", "method": "exit"}"#;
+        let input = format!(
+            "Content-Length: 3\r\n\r\n{{x}}Content-Length: {}\r\n\r\n{}Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body,
+            exit.len(),
+            exit
+        );
+        let mut output = vec![];
+        LanguageServer::new()
+            .run(input.as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let parts: Vec<&str> = output.split("Content-Length: ").skip(1).collect();
+        assert_eq!(parts.len(), 2);
+        let mut replies = vec![];
+        for part in parts {
+            let body = part.splitn(2, "\r\n\r\n").nth(1).unwrap();
+            assert_eq!(part, format!("{}\r\n\r\n{}", body.len(), body));
+            replies.push(serde_json::from_str::<serde_json::Value>(body).unwrap());
+        }
+        assert_eq!(replies[0]["id"], json!(null));
+        assert_eq!(replies[0]["error"]["code"], -32700);
+        assert_eq!(
+            replies[1],
+            json!({"jsonrpc": "2.0", "id": 7, "result": null})
+        );
+    }
+
+    #[test]
+    fn uris() {
+        let path = PathBuf::from("/tmp/my docs/caf\u{e9}%.graft");
+        assert_eq!(
+            super::file_uri(&path),
+            "file:///tmp/my%20docs/caf%C3%A9%25.graft"
+        );
+        assert_eq!(super::path(&super::file_uri(&path)), Some(path));
+    }
+}
@@ -0,0 +1,265 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{err_msg, Error};
+use graft::{CachedContext, DirContext, Level, LintConfig, Output, Params, Rule};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    process,
+};
+
+fn main() {
+    let matches = App::new("graft")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("json tree builder")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts a graft file to json")
+                .arg(Arg::with_name("input").required(true))
+                .arg(output_arg())
+                .args(&context_args())
+                .args(&param_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Converts all graft files in a directory tree, to json files by default")
+                .arg(Arg::with_name("input").required(true))
+                .arg(Arg::with_name("output").required(true))
+                .arg(output_arg())
+                .args(&context_args())
+                .args(&param_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Reports likely mistakes in a graft file")
+                .arg(Arg::with_name("input").required(true))
+                .args(&context_args())
+                .args(&param_args())
+                .args(&level_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Shows where the value at a json pointer, and the values containing it, came from")
+                .arg(Arg::with_name("input").required(true))
+                .arg(Arg::with_name("pointer").required(true))
+                .args(&context_args())
+                .args(&param_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Rewrites graft files in canonical form")
+                .arg(Arg::with_name("input").required(true).multiple(true))
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Only lists files that are not formatted, failing if there are any"),
+                ),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("convert", Some(m)) => convert(m),
+        ("batch", Some(m)) => batch(m),
+        ("explain", Some(m)) => explain(m),
+        ("fmt", Some(m)) => fmt(m),
+        ("lint", Some(m)) => lint(m),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn context_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![Arg::with_name("templates")
+        .long("templates")
+        .short("t")
+        .takes_value(true)
+        .help("Directory to look up templates in, defaults to the input's directory")]
+}
+
+fn param_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("param")
+            .long("param")
+            .short("p")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("key=value")
+            .help("Sets a parameter, available as ${key}"),
+        Arg::with_name("env")
+            .long("env")
+            .takes_value(true)
+            .help("Sets the parameter env.<name> to true"),
+        Arg::with_name("lenient")
+            .long("lenient")
+            .help("Treats a referenced parameter or variable that is missing as null"),
+    ]
+}
+
+fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("output")
+        .short("o")
+        .takes_value(true)
+        .value_name("format")
+        .help("Output format: json (the default), yaml or yml, toml, msgpack or messagepack, or cbor")
+}
+
+fn level_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    ["allow", "warn", "deny"]
+        .iter()
+        .map(|level| {
+            Arg::with_name(level)
+                .long(level)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("rule")
+        })
+        .collect()
+}
+
+/// Looks up templates in `--templates`, if given, else in `dir`.
+fn context(m: &ArgMatches, dir: &Path) -> CachedContext<DirContext> {
+    let dir = m.value_of("templates").map_or(dir, Path::new);
+    CachedContext::new(Box::new(DirContext::new(dir.to_path_buf())))
+}
+
+fn params(m: &ArgMatches) -> Result<Params, Error> {
+    let mut params = Params::new().strict(!m.is_present("lenient"));
+    for param in m.values_of("param").into_iter().flatten() {
+        params = params.parse(param)?;
+    }
+    if let Some(env) = m.value_of("env") {
+        params = params.env(env);
+    }
+    Ok(params)
+}
+
+fn convert(m: &ArgMatches) -> Result<(), Error> {
+    let input = Path::new(m.value_of("input").unwrap()); // safe because it is required
+    let dir = input.parent().unwrap_or_else(|| Path::new("."));
+    let output = Output::from_name(m.value_of("format").unwrap_or("json"))?;
+    let value = graft::convert_file(input, &context(m, dir), &params(m)?)?;
+    io::stdout().write_all(&graft::serialize(&value, output)?)?;
+    Ok(())
+}
+
+fn batch(m: &ArgMatches) -> Result<(), Error> {
+    let input = Path::new(m.value_of("input").unwrap()); // safe because it is required
+    let output = Path::new(m.value_of("output").unwrap()); // safe because it is required
+    let format = Output::from_name(m.value_of("format").unwrap_or("json"))?;
+    let report = graft::convert_dir(input, output, format, &context(m, input), &params(m)?)?;
+    for (path, e) in &report.failed {
+        eprintln!("error: {}: {}", path.display(), e);
+    }
+    println!(
+        "converted {} files, {} failed",
+        report.converted.len(),
+        report.failed.len()
+    );
+    if report.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(err_msg("some files could not be converted"))
+    }
+}
+
+fn explain(m: &ArgMatches) -> Result<(), Error> {
+    let input = Path::new(m.value_of("input").unwrap()); // safe because it is required
+    let pointer = m.value_of("pointer").unwrap().trim_end_matches('/'); // safe because it is required
+    let txt = fs::read_to_string(input)
+        .map_err(|e| err_msg(format!("can not read {}: {}", input.display(), e)))?;
+    let dir = input.parent().unwrap_or_else(|| Path::new("."));
+    let (_, provenance) = graft::convert_with_provenance(&txt, &context(m, dir), &params(m)?)?;
+    if !provenance.contains_key(pointer) {
+        return Err(err_msg(format!("no value at {}", pointer)));
+    }
+
+    let mut prefix = String::new();
+    for key in pointer.split('/') {
+        if !key.is_empty() {
+            prefix = prefix + "/" + key;
+        }
+        let mut origin = match provenance.get(&prefix) {
+            Some(origin) => origin.clone(),
+            None => continue,
+        };
+        if origin.file.is_none() {
+            origin.file = Some(input.display().to_string());
+        }
+        let shown: &str = if prefix == "" { "/" } else { &prefix };
+        println!("{}: {}", shown, origin);
+    }
+    Ok(())
+}
+
+fn fmt(m: &ArgMatches) -> Result<(), Error> {
+    let mut unformatted = 0;
+    let inputs = m.values_of("input").unwrap(); // safe because it is required
+    for input in inputs {
+        let txt = fs::read_to_string(input)
+            .map_err(|e| err_msg(format!("can not read {}: {}", input, e)))?;
+        let formatted =
+            graft::format_document(&txt).map_err(|e| err_msg(format!("{}: {}", input, e)))?;
+        if formatted == txt {
+            continue;
+        }
+        if m.is_present("check") {
+            println!("{}", input);
+            unformatted += 1;
+        } else {
+            fs::write(input, formatted)?;
+        }
+    }
+    if unformatted > 0 {
+        return Err(err_msg(format!("{} files are not formatted", unformatted)));
+    }
+    Ok(())
+}
+
+fn lint(m: &ArgMatches) -> Result<(), Error> {
+    let input = Path::new(m.value_of("input").unwrap()); // safe because it is required
+    let mut config = LintConfig::new();
+    for (arg, level) in &[
+        ("allow", Level::Allow),
+        ("warn", Level::Warn),
+        ("deny", Level::Deny),
+    ] {
+        for rule in m.values_of(arg).into_iter().flatten() {
+            config = config.with(Rule::from_name(rule)?, *level);
+        }
+    }
+
+    let txt = fs::read_to_string(input)
+        .map_err(|e| err_msg(format!("can not read {}: {}", input.display(), e)))?;
+    let dir = input.parent().unwrap_or_else(|| Path::new("."));
+    let lints = graft::lint(&txt, &context(m, dir), &params(m)?, &config)?;
+    for l in &lints {
+        let level = if l.level == Level::Deny {
+            "error"
+        } else {
+            "warning"
+        };
+        let file = match l.file {
+            Some(ref file) => file.to_string(),
+            None => input.display().to_string(),
+        };
+        println!(
+            "{}: {}:{}: {} [{}]",
+            level,
+            file,
+            l.line,
+            l.message,
+            l.rule.name()
+        );
+    }
+    if lints.iter().any(|l| l.level == Level::Deny) {
+        return Err(err_msg("denied lints found"));
+    }
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use crate::schema;
+use failure::{err_msg, Error};
+use rmp_serde;
+use serde_cbor;
+use serde_json;
+use serde_yaml;
+use toml;
+
+/// A serialization for converted values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Pretty printed json.
+    JSON,
+    YAML,
+    /// Only for objects without nulls, whose arrays do not mix types.
+    TOML,
+    MessagePack,
+    CBOR,
+}
+
+impl Output {
+    pub fn all() -> Vec<Output> {
+        vec![
+            Output::JSON,
+            Output::YAML,
+            Output::TOML,
+            Output::MessagePack,
+            Output::CBOR,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Output::JSON => "json",
+            Output::YAML => "yaml",
+            Output::TOML => "toml",
+            Output::MessagePack => "msgpack",
+            Output::CBOR => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Output, Error> {
+        match name.to_lowercase().as_ref() {
+            "yml" => Ok(Output::YAML),
+            "messagepack" => Ok(Output::MessagePack),
+            lower => Output::all()
+                .into_iter()
+                .find(|o| o.name() == lower)
+                .ok_or_else(|| err_msg(format!("unknown output format: {}", name))),
+        }
+    }
+}
+
+/// Serializes a converted value. The text formats end with a newline.
+pub fn serialize(value: &serde_json::Value, output: Output) -> Result<Vec<u8>, Error> {
+    Ok(match output {
+        Output::JSON => (serde_json::to_string_pretty(value)? + "\n").into_bytes(),
+        Output::YAML => (serde_yaml::to_string(value)? + "\n").into_bytes(),
+        Output::TOML => {
+            if !value.is_object() {
+                return Err(err_msg(format!(
+                    "toml needs an object at the top, not {}",
+                    kind(value)
+                )));
+            }
+            representable(value, "")?;
+            // converting first, as toml::Value puts plain values before tables
+            let value = toml::Value::try_from(value)?;
+            toml::to_string(&value)?.into_bytes()
+        }
+        Output::MessagePack => rmp_serde::to_vec(value)?,
+        Output::CBOR => serde_cbor::to_vec(value)?,
+    })
+}
+
+/// Checks that toml can represent everything in `value`, which is at
+/// `pointer`.
+fn representable(value: &serde_json::Value, pointer: &str) -> Result<(), Error> {
+    let shown = if pointer == "" { "/" } else { pointer };
+    match value {
+        serde_json::Value::Null => Err(err_msg(format!("toml can not represent null: {}", shown))),
+        serde_json::Value::Object(o) => {
+            for (k, v) in o {
+                representable(v, &format!("{}/{}", pointer, schema::escape(k)))?;
+            }
+            Ok(())
+        }
+        serde_json::Value::Array(a) => {
+            if let Some(first) = a.first() {
+                if let Some(other) = a.iter().find(|v| kind(v) != kind(first)) {
+                    return Err(err_msg(format!(
+                        "toml arrays can not mix {} and {}: {}",
+                        kind(first),
+                        kind(other),
+                        shown
+                    )));
+                }
+            }
+            for (i, v) in a.iter().enumerate() {
+                representable(v, &format!("{}/{}", pointer, i))?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn kind(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(n) if n.is_f64() => "a float",
+        serde_json::Value::Number(_) => "an integer",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serialize, Output};
+
+    #[test]
+    fn outputs() {
+        let value = json!({"title": "hi", "meta": {"tags": ["a", "b"]}, "count": 2});
+        let text = |output| String::from_utf8(serialize(&value, output).unwrap()).unwrap();
+
+        #[cfg(not(feature = "preserve_order"))]
+        assert_eq!(
+            text(Output::TOML),
+            "count = 2\ntitle = \"hi\"\n\n[meta]\ntags = [\"a\", \"b\"]\n"
+        );
+        #[cfg(feature = "preserve_order")]
+        assert_eq!(
+            text(Output::TOML),
+            "title = \"hi\"\ncount = 2\n\n[meta]\ntags = [\"a\", \"b\"]\n"
+        );
+        assert_eq!(
+            serde_yaml::from_str::<serde_json::Value>(&text(Output::YAML)).unwrap(),
+            value
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text(Output::JSON)).unwrap(),
+            value
+        );
+        assert_eq!(
+            rmp_serde::from_slice::<serde_json::Value>(
+                &serialize(&value, Output::MessagePack).unwrap()
+            )
+            .unwrap(),
+            value
+        );
+        assert_eq!(
+            serde_cbor::from_slice::<serde_json::Value>(&serialize(&value, Output::CBOR).unwrap())
+                .unwrap(),
+            value
+        );
+
+        let error =
+            |value: serde_json::Value| serialize(&value, Output::TOML).unwrap_err().to_string();
+        assert_eq!(
+            error(json!([1])),
+            "toml needs an object at the top, not an array"
+        );
+        assert_eq!(
+            error(json!({"a": {"b~": null}})),
+            "toml can not represent null: /a/b~0"
+        );
+        assert_eq!(
+            error(json!({"a": [1, 1.5]})),
+            "toml arrays can not mix an integer and a float: /a"
+        );
+        assert_eq!(Output::from_name("YML").unwrap(), Output::YAML);
+        assert!(Output::from_name("xml").is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use failure::Error;
+use std::borrow::Cow;
+use std::io::BufRead;
+
+/// A section as written in the source, borrowing from it.
+#[derive(Debug, PartialEq)]
+pub struct RawSection<'a> {
+    /// The header line, including the leading `--`.
+    pub header: &'a str,
+    /// Everything up to the next header, with `/--` escapes still in place.
+    pub body: &'a str,
+    /// Line of the header, 1 based.
+    pub line: usize,
+    /// Byte offset of the header in the source.
+    pub offset: usize,
+    /// Byte offset of the body in the source.
+    pub body_offset: usize,
+}
+
+impl<'a> RawSection<'a> {
+    /// The body with `/--` unescaped, only allocating if there are any.
+    pub fn unescaped_body(&self) -> Cow<'a, str> {
+        unescape(self.body)
+    }
+}
+
+fn unescape(body: &str) -> Cow<'_, str> {
+    if body.contains("/--") {
+        Cow::Owned(body.replace("/--", "--"))
+    } else {
+        Cow::Borrowed(body)
+    }
+}
+
+/// Returns an iterator over the sections of `txt`. Sections start at lines
+/// beginning with `--`; anything before the first one is skipped.
+pub fn scan(txt: &str) -> Scan<'_> {
+    let offset = if txt.starts_with("--") {
+        0
+    } else {
+        txt.find("\n--").map_or(txt.len(), |i| i + 1)
+    };
+    Scan {
+        txt,
+        offset,
+        line: 1 + txt[..offset].matches('\n').count(),
+    }
+}
+
+/// Iterator returned by `scan`.
+pub struct Scan<'a> {
+    txt: &'a str,
+    /// Offset of the next header, or the length of `txt` when done.
+    offset: usize,
+    line: usize,
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = RawSection<'a>;
+
+    fn next(&mut self) -> Option<RawSection<'a>> {
+        let txt = self.txt;
+        if self.offset >= txt.len() {
+            return None;
+        }
+        let start = self.offset;
+        let header_end = txt[start..].find('\n').map_or(txt.len(), |i| start + i);
+        let body_offset = (header_end + 1).min(txt.len());
+        let next = txt[header_end..]
+            .find("\n--")
+            .map_or(txt.len(), |i| header_end + i + 1);
+
+        let section = RawSection {
+            header: &txt[start..header_end],
+            body: &txt[body_offset..next],
+            line: self.line,
+            offset: start,
+            body_offset,
+        };
+        self.line += txt[start..next].matches('\n').count();
+        self.offset = next;
+        Some(section)
+    }
+}
+
+/// A section read by `Reader`, owning its text.
+#[derive(Debug, PartialEq)]
+pub struct OwnedSection {
+    /// The header line, including the leading `--`.
+    pub header: String,
+    /// Everything up to the next header, with `/--` escapes still in place.
+    pub body: String,
+    /// Line of the header, 1 based.
+    pub line: usize,
+    /// Byte offset of the header in the source.
+    pub offset: usize,
+    /// Byte offset of the body in the source.
+    pub body_offset: usize,
+}
+
+impl OwnedSection {
+    /// The body with `/--` unescaped.
+    pub fn unescaped_body(&self) -> Cow<'_, str> {
+        unescape(&self.body)
+    }
+}
+
+/// Reads sections from a `BufRead` one at a time, so only the current
+/// section is held in memory. Yields the same sections as `scan`.
+pub struct Reader<R> {
+    reader: R,
+    /// The header line read ahead while reading the previous body.
+    next: Option<(String, usize, usize)>,
+    line: usize,
+    offset: usize,
+    done: bool,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Reader<R> {
+        Reader {
+            reader,
+            next: None,
+            line: 0,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Reads a line, returning it with its line number and offset.
+    fn read_line(&mut self) -> Result<Option<(String, usize, usize)>, Error> {
+        let mut buf = String::new();
+        if self.reader.read_line(&mut buf)? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        let offset = self.offset;
+        self.offset += buf.len();
+        Ok(Some((buf, self.line, offset)))
+    }
+
+    fn read_section(&mut self) -> Result<Option<OwnedSection>, Error> {
+        let (mut header, line, offset) = match self.next.take() {
+            Some(next) => next,
+            None => loop {
+                match self.read_line()? {
+                    Some(l) => {
+                        if l.0.starts_with("--") {
+                            break l;
+                        }
+                    }
+                    None => return Ok(None),
+                }
+            },
+        };
+
+        let body_offset = offset + header.len();
+        let mut body = String::new();
+        while let Some(l) = self.read_line()? {
+            if l.0.starts_with("--") {
+                self.next = Some(l);
+                break;
+            }
+            body += &l.0;
+        }
+        if header.ends_with('\n') {
+            header.pop();
+        }
+        Ok(Some(OwnedSection {
+            header,
+            body,
+            line,
+            offset,
+            body_offset,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<OwnedSection, Error>;
+
+    fn next(&mut self) -> Option<Result<OwnedSection, Error>> {
+        if self.done {
+            return None;
+        }
+        match self.read_section() {
+            Ok(Some(section)) => Some(Ok(section)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scan, OwnedSection, RawSection, Reader};
+
+    #[test]
+    fn scan_and_read() {
+        let txt = "preamble\n-- $page\ntitle: yo\n-- @body ~text\nsome /-- text\n--\n";
+        let sections: Vec<RawSection> = scan(txt).collect();
+        assert_eq!(
+            sections,
+            vec![
+                RawSection {
+                    header: "-- $page",
+                    body: "title: yo\n",
+                    line: 2,
+                    offset: 9,
+                    body_offset: 18,
+                },
+                RawSection {
+                    header: "-- @body ~text",
+                    body: "some /-- text\n",
+                    line: 4,
+                    offset: 28,
+                    body_offset: 43,
+                },
+                RawSection {
+                    header: "--",
+                    body: "",
+                    line: 6,
+                    offset: 57,
+                    body_offset: 60,
+                },
+            ]
+        );
+        assert_eq!(sections[1].unescaped_body(), "some -- text\n");
+
+        let read: Vec<OwnedSection> = Reader::new(txt.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read.len(), sections.len());
+        for (read, raw) in read.iter().zip(&sections) {
+            assert_eq!(read.header, raw.header);
+            assert_eq!(read.body, raw.body);
+            assert_eq!(
+                (read.line, read.offset, read.body_offset),
+                (raw.line, raw.offset, raw.body_offset)
+            );
+        }
+
+        assert_eq!(scan("no sections").count(), 0);
+        assert_eq!(
+            scan("-- a\n-- b").map(|s| s.body).collect::<Vec<_>>(),
+            vec!["", ""]
+        );
+    }
+}
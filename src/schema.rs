@@ -0,0 +1,647 @@
+use failure::{err_msg, Error};
+use regex::Regex;
+use serde_json;
+use std::collections::HashMap;
+
+/// A place where a value does not match its schema.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    /// JSON pointer to the offending value, relative to the validated value.
+    pub pointer: String,
+    pub message: String,
+}
+
+/// Validates `value` against a JSON Schema.
+///
+/// These keywords are checked: `type`, `enum`, `const`, `allOf`, `anyOf`,
+/// `oneOf`, `not`, `if`/`then`/`else`, `properties`, `patternProperties`,
+/// `additionalProperties`, `propertyNames`, `required`, `minProperties`,
+/// `maxProperties`, `dependencies`, `items`, `additionalItems`, `contains`,
+/// `minItems`, `maxItems`, `uniqueItems`, `minLength`, `maxLength`,
+/// `pattern`, `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`,
+/// `multipleOf` and local `$ref`s like `#/definitions/foo`. Annotations like
+/// `title` or `format` are ignored. Any other keyword, an invalid pattern, an
+/// unresolvable `$ref` or one that refers back to itself is an error of the
+/// schema, so a schema never checks less than it says. Violations are sorted
+/// by pointer.
+pub fn validate(
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+) -> Result<Vec<Violation>, Error> {
+    let mut checker = Checker {
+        root: schema,
+        patterns: HashMap::new(),
+        refs: vec![],
+        cycle: None,
+    };
+    checker.walk(schema, "")?;
+    let mut violations = vec![];
+    checker.check(schema, value, "", &mut violations);
+    if let Some(r) = checker.cycle {
+        return Err(err_msg(format!("$ref refers to itself: {}", r)));
+    }
+    // stable, so violations of one value keep the order of the keywords
+    violations.sort_by(|a, b| a.pointer.cmp(&b.pointer));
+    Ok(violations)
+}
+
+/// The keywords `check` knows.
+const KEYWORDS: &[&str] = &[
+    "$ref",
+    "type",
+    "enum",
+    "const",
+    "allOf",
+    "anyOf",
+    "oneOf",
+    "not",
+    "if",
+    "then",
+    "else",
+    "required",
+    "properties",
+    "patternProperties",
+    "additionalProperties",
+    "propertyNames",
+    "minProperties",
+    "maxProperties",
+    "dependencies",
+    "items",
+    "additionalItems",
+    "contains",
+    "minItems",
+    "maxItems",
+    "uniqueItems",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "multipleOf",
+];
+
+/// Keywords that only describe values, with nothing to check.
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "definitions",
+    "$defs",
+    "format",
+    "readOnly",
+    "writeOnly",
+    "deprecated",
+    "contentMediaType",
+    "contentEncoding",
+];
+
+struct Checker<'a> {
+    root: &'a serde_json::Value,
+    /// Compiled `pattern`s and `patternProperties` keys.
+    patterns: HashMap<&'a str, Regex>,
+    /// The `$ref`s being checked, with the pointer of the value each checks.
+    refs: Vec<(&'a str, String)>,
+    /// A `$ref` found to check the same value again, forever.
+    cycle: Option<String>,
+}
+
+impl<'a> Checker<'a> {
+    /// Checks the schema itself, and compiles its patterns.
+    fn walk(&mut self, schema: &'a serde_json::Value, pointer: &str) -> Result<(), Error> {
+        let schema = match schema {
+            serde_json::Value::Object(o) => o,
+            _ => return Ok(()),
+        };
+        for (key, sub) in schema {
+            let child = pointer.to_string() + "/" + &escape(key);
+            match (key.as_str(), sub) {
+                ("$ref", serde_json::Value::String(r)) => {
+                    if !r.starts_with('#') || self.root.pointer(&r[1..]).is_none() {
+                        return Err(err_msg(format!("unresolvable $ref: {}", r)));
+                    }
+                }
+                ("pattern", serde_json::Value::String(p)) => self.compile(p)?,
+                ("allOf", serde_json::Value::Array(a))
+                | ("anyOf", serde_json::Value::Array(a))
+                | ("oneOf", serde_json::Value::Array(a))
+                | ("items", serde_json::Value::Array(a)) => {
+                    for (idx, s) in a.iter().enumerate() {
+                        self.walk(s, &format!("{}/{}", child, idx))?;
+                    }
+                }
+                ("properties", serde_json::Value::Object(o))
+                | ("patternProperties", serde_json::Value::Object(o))
+                | ("dependencies", serde_json::Value::Object(o))
+                | ("definitions", serde_json::Value::Object(o))
+                | ("$defs", serde_json::Value::Object(o)) => {
+                    for (k, s) in o {
+                        if key == "patternProperties" {
+                            self.compile(k)?;
+                        }
+                        self.walk(s, &(child.clone() + "/" + &escape(k)))?;
+                    }
+                }
+                ("not", _)
+                | ("if", _)
+                | ("then", _)
+                | ("else", _)
+                | ("items", _)
+                | ("additionalItems", _)
+                | ("additionalProperties", _)
+                | ("propertyNames", _)
+                | ("contains", _) => self.walk(sub, &child)?,
+                (k, _) if KEYWORDS.contains(&k) || ANNOTATIONS.contains(&k) => {}
+                (k, _) => {
+                    let at = if pointer == "" { "/" } else { pointer };
+                    return Err(err_msg(format!("unsupported schema keyword {}: {}", k, at)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compile(&mut self, pattern: &'a str) -> Result<(), Error> {
+        if !self.patterns.contains_key(pattern) {
+            let re = Regex::new(pattern)
+                .map_err(|_| err_msg(format!("invalid pattern: {}", pattern)))?;
+            self.patterns.insert(pattern, re);
+        }
+        Ok(())
+    }
+
+    fn is_match(&self, pattern: &str, s: &str) -> bool {
+        // safe because `walk` compiled every pattern of the schema
+        self.patterns[pattern].is_match(s)
+    }
+
+    fn check(
+        &mut self,
+        schema: &'a serde_json::Value,
+        value: &serde_json::Value,
+        pointer: &str,
+        violations: &mut Vec<Violation>,
+    ) {
+        let schema = match schema {
+            serde_json::Value::Bool(true) => return,
+            serde_json::Value::Bool(false) => {
+                return fail(violations, pointer, "no value is allowed here".into())
+            }
+            serde_json::Value::Object(o) => o,
+            _ => return,
+        };
+
+        if let Some(r) = schema.get("$ref").and_then(|r| r.as_str()) {
+            if self.refs.iter().any(|(other, p)| *other == r && p == pointer) {
+                self.cycle = Some(r.into());
+                return;
+            }
+            // safe because `walk` resolved every $ref of the schema
+            let s = self.root.pointer(&r[1..]).unwrap();
+            self.refs.push((r, pointer.into()));
+            self.check(s, value, pointer, violations);
+            self.refs.pop();
+            return;
+        }
+
+        if let Some(t) = schema.get("type") {
+            let ok = match t {
+                serde_json::Value::String(t) => is_type(t, value),
+                serde_json::Value::Array(a) => a
+                    .iter()
+                    .any(|t| t.as_str().map_or(false, |t| is_type(t, value))),
+                _ => true,
+            };
+            if !ok {
+                fail(violations, pointer, format!("expected type {}, found {}", t, value));
+            }
+        }
+
+        if let Some(serde_json::Value::Array(e)) = schema.get("enum") {
+            if !e.contains(value) {
+                let e = serde_json::Value::Array(e.clone());
+                fail(violations, pointer, format!("{} is not one of {}", value, e));
+            }
+        }
+
+        if let Some(c) = schema.get("const") {
+            if c != value {
+                fail(violations, pointer, format!("expected {}, found {}", c, value));
+            }
+        }
+
+        if let Some(serde_json::Value::Array(all)) = schema.get("allOf") {
+            for s in all {
+                self.check(s, value, pointer, violations);
+            }
+        }
+
+        if let Some(serde_json::Value::Array(any)) = schema.get("anyOf") {
+            if !any.iter().any(|s| self.matches(s, value, pointer)) {
+                fail(violations, pointer, "does not match any schema in anyOf".into());
+            }
+        }
+
+        if let Some(serde_json::Value::Array(one)) = schema.get("oneOf") {
+            let count = one
+                .iter()
+                .filter(|s| self.matches(s, value, pointer))
+                .count();
+            if count != 1 {
+                fail(
+                    violations,
+                    pointer,
+                    format!("matches {} schemas in oneOf, expected exactly one", count),
+                );
+            }
+        }
+
+        if let Some(not) = schema.get("not") {
+            if self.matches(not, value, pointer) {
+                fail(violations, pointer, "matches the schema in not".into());
+            }
+        }
+
+        if let Some(cond) = schema.get("if") {
+            let branch = if self.matches(cond, value, pointer) {
+                schema.get("then")
+            } else {
+                schema.get("else")
+            };
+            if let Some(s) = branch {
+                self.check(s, value, pointer, violations);
+            }
+        }
+
+        if let (Some(serde_json::Value::Object(deps)), Some(o)) =
+            (schema.get("dependencies"), value.as_object())
+        {
+            for (key, dep) in deps.iter().filter(|(k, _)| o.contains_key(k.as_str())) {
+                match dep {
+                    serde_json::Value::Array(keys) => {
+                        for k in keys.iter().filter_map(|k| k.as_str()) {
+                            if !o.contains_key(k) {
+                                fail(violations, pointer, format!("{} needs key: {}", key, k));
+                            }
+                        }
+                    }
+                    s => self.check(s, value, pointer, violations),
+                }
+            }
+        }
+
+        match value {
+            serde_json::Value::Object(o) => self.check_object(schema, o, pointer, violations),
+            serde_json::Value::Array(a) => self.check_array(schema, a, pointer, violations),
+            serde_json::Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(|m| m.as_u64()) {
+                    if len < min {
+                        fail(violations, pointer, format!("shorter than {} characters", min));
+                    }
+                }
+                if let Some(max) = schema.get("maxLength").and_then(|m| m.as_u64()) {
+                    if len > max {
+                        fail(violations, pointer, format!("longer than {} characters", max));
+                    }
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(|p| p.as_str()) {
+                    if !self.is_match(pattern, s) {
+                        fail(violations, pointer, format!("does not match {}", pattern));
+                    }
+                }
+            }
+            serde_json::Value::Number(n) => {
+                let n = n.as_f64().unwrap_or(0.0);
+                check_number(schema, n, pointer, violations);
+            }
+            _ => {}
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        schema: &'a serde_json::Map<String, serde_json::Value>,
+        o: &serde_json::Map<String, serde_json::Value>,
+        pointer: &str,
+        violations: &mut Vec<Violation>,
+    ) {
+        if let Some(serde_json::Value::Array(required)) = schema.get("required") {
+            for key in required.iter().filter_map(|k| k.as_str()) {
+                if !o.contains_key(key) {
+                    fail(violations, pointer, format!("missing required key: {}", key));
+                }
+            }
+        }
+        if let Some(min) = schema.get("minProperties").and_then(|m| m.as_u64()) {
+            if (o.len() as u64) < min {
+                fail(violations, pointer, format!("expected at least {} keys", min));
+            }
+        }
+        if let Some(max) = schema.get("maxProperties").and_then(|m| m.as_u64()) {
+            if (o.len() as u64) > max {
+                fail(violations, pointer, format!("expected at most {} keys", max));
+            }
+        }
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+        let patterns = schema.get("patternProperties").and_then(|p| p.as_object());
+        for (k, v) in o {
+            let child = pointer.to_string() + "/" + &escape(k);
+            if let Some(s) = schema.get("propertyNames") {
+                self.check(s, &serde_json::Value::String(k.clone()), &child, violations);
+            }
+            let mut matched = false;
+            if let Some(s) = properties.and_then(|p| p.get(k)) {
+                self.check(s, v, &child, violations);
+                matched = true;
+            }
+            for (pattern, s) in patterns.into_iter().flatten() {
+                if self.is_match(pattern, k) {
+                    self.check(s, v, &child, violations);
+                    matched = true;
+                }
+            }
+            if matched {
+                continue;
+            }
+            match schema.get("additionalProperties") {
+                Some(serde_json::Value::Bool(false)) => {
+                    fail(violations, &child, format!("unexpected key: {}", k))
+                }
+                Some(s) => self.check(s, v, &child, violations),
+                None => {}
+            }
+        }
+    }
+
+    fn check_array(
+        &mut self,
+        schema: &'a serde_json::Map<String, serde_json::Value>,
+        a: &[serde_json::Value],
+        pointer: &str,
+        violations: &mut Vec<Violation>,
+    ) {
+        if let Some(min) = schema.get("minItems").and_then(|m| m.as_u64()) {
+            if (a.len() as u64) < min {
+                fail(violations, pointer, format!("expected at least {} items", min));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(|m| m.as_u64()) {
+            if (a.len() as u64) > max {
+                fail(violations, pointer, format!("expected at most {} items", max));
+            }
+        }
+        if schema.get("uniqueItems") == Some(&serde_json::Value::Bool(true)) {
+            for (idx, v) in a.iter().enumerate() {
+                if let Some(first) = a[..idx].iter().position(|other| other == v) {
+                    let child = format!("{}/{}", pointer, idx);
+                    fail(violations, &child, format!("same as item {}", first));
+                }
+            }
+        }
+        if let Some(s) = schema.get("contains") {
+            let found = a
+                .iter()
+                .enumerate()
+                .any(|(idx, v)| self.matches(s, v, &format!("{}/{}", pointer, idx)));
+            if !found {
+                fail(violations, pointer, "no item matches the schema in contains".into());
+            }
+        }
+        match schema.get("items") {
+            Some(serde_json::Value::Array(items)) => {
+                for (idx, v) in a.iter().enumerate() {
+                    let s = match (items.get(idx), schema.get("additionalItems")) {
+                        (Some(s), _) | (None, Some(s)) => s,
+                        (None, None) => break,
+                    };
+                    self.check(s, v, &format!("{}/{}", pointer, idx), violations);
+                }
+            }
+            Some(s) => {
+                for (idx, v) in a.iter().enumerate() {
+                    self.check(s, v, &format!("{}/{}", pointer, idx), violations);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Whether `value`, at `pointer`, matches `schema`.
+    fn matches(
+        &mut self,
+        schema: &'a serde_json::Value,
+        value: &serde_json::Value,
+        pointer: &str,
+    ) -> bool {
+        let mut violations = vec![];
+        self.check(schema, value, pointer, &mut violations);
+        violations.is_empty()
+    }
+}
+
+fn check_number(
+    schema: &serde_json::Map<String, serde_json::Value>,
+    n: f64,
+    pointer: &str,
+    violations: &mut Vec<Violation>,
+) {
+    // draft 4 writes exclusive bounds as booleans next to the bounds
+    let exclusive = |key| schema.get(key) == Some(&serde_json::Value::Bool(true));
+    if let Some(min) = schema.get("minimum").and_then(|m| m.as_f64()) {
+        if n < min || (n == min && exclusive("exclusiveMinimum")) {
+            fail(violations, pointer, format!("{} is less than {}", n, min));
+        }
+    }
+    if let Some(max) = schema.get("maximum").and_then(|m| m.as_f64()) {
+        if n > max || (n == max && exclusive("exclusiveMaximum")) {
+            fail(violations, pointer, format!("{} is more than {}", n, max));
+        }
+    }
+    if let Some(min) = schema.get("exclusiveMinimum").and_then(|m| m.as_f64()) {
+        if n <= min {
+            fail(violations, pointer, format!("{} is not more than {}", n, min));
+        }
+    }
+    if let Some(max) = schema.get("exclusiveMaximum").and_then(|m| m.as_f64()) {
+        if n >= max {
+            fail(violations, pointer, format!("{} is not less than {}", n, max));
+        }
+    }
+    if let Some(m) = schema.get("multipleOf").and_then(|m| m.as_f64()) {
+        let q = n / m;
+        // allows for rounding, like 0.3 / 0.1
+        if m > 0.0 && (q - q.round()).abs() > 1e-9 {
+            fail(violations, pointer, format!("{} is not a multiple of {}", n, m));
+        }
+    }
+}
+
+fn is_type(t: &str, value: &serde_json::Value) -> bool {
+    match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().map_or(false, |f| f.fract() == 0.0)
+        }
+        _ => true,
+    }
+}
+
+fn fail(violations: &mut Vec<Violation>, pointer: &str, message: String) {
+    violations.push(Violation {
+        pointer: pointer.to_string(),
+        message,
+    })
+}
+
+/// Escapes a key for use in a JSON pointer.
+pub fn escape(key: &str) -> String {
+    key.replace("~", "~0").replace("/", "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Violation;
+
+    #[test]
+    fn validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["title"],
+            "properties": {
+                "title": {"type": "string", "minLength": 1},
+                "tags": {"type": "array", "items": {"$ref": "#/definitions/tag"}},
+            },
+            "additionalProperties": false,
+            "definitions": {
+                "tag": {"enum": ["a", "b"]}
+            }
+        });
+
+        assert_eq!(
+            super::validate(&schema, &json!({"title": "yo", "tags": ["a"]})).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            super::validate(&schema, &json!({"tags": ["a", "c"], "x/y": 1})).unwrap(),
+            vec![
+                Violation {
+                    pointer: "".into(),
+                    message: "missing required key: title".into(),
+                },
+                Violation {
+                    pointer: "/tags/1".into(),
+                    message: r#""c" is not one of ["a","b"]"#.into(),
+                },
+                Violation {
+                    pointer: "/x~1y".into(),
+                    message: "unexpected key: x/y".into(),
+                },
+            ]
+        );
+
+        let schema = json!({
+            "patternProperties": {"^x-": {"type": "string", "pattern": "^[a-z]+$"}},
+            "additionalProperties": false,
+            "format": "strict",
+        });
+        assert_eq!(
+            super::validate(&schema, &json!({"x-a": "ok", "x-b": "Not", "y": 1})).unwrap(),
+            vec![
+                Violation {
+                    pointer: "/x-b".into(),
+                    message: "does not match ^[a-z]+$".into(),
+                },
+                Violation {
+                    pointer: "/y".into(),
+                    message: "unexpected key: y".into(),
+                },
+            ]
+        );
+
+        let schema = json!({
+            "properties": {
+                "tags": {"uniqueItems": true, "contains": {"const": "new"}},
+                "size": {"exclusiveMinimum": 0, "multipleOf": 0.1},
+                "meta": {"propertyNames": {"pattern": "^[a-z]+$"}, "maxProperties": 1},
+            },
+            "dependencies": {"size": ["unit"]},
+            "if": {"required": ["size"]},
+            "then": {"minProperties": 4},
+        });
+        assert_eq!(
+            super::validate(&schema, &json!({"tags": ["new"], "size": 0.3, "unit": "cm"}))
+                .unwrap(),
+            vec![Violation {
+                pointer: "".into(),
+                message: "expected at least 4 keys".into(),
+            }]
+        );
+        assert_eq!(
+            super::validate(&schema, &json!({"tags": ["a", "a"], "size": 0, "meta": {"A": 1}}))
+                .unwrap(),
+            vec![
+                Violation {
+                    pointer: "".into(),
+                    message: "expected at least 4 keys".into(),
+                },
+                Violation {
+                    pointer: "".into(),
+                    message: "size needs key: unit".into(),
+                },
+                Violation {
+                    pointer: "/meta/A".into(),
+                    message: "does not match ^[a-z]+$".into(),
+                },
+                Violation {
+                    pointer: "/size".into(),
+                    message: "0 is not more than 0".into(),
+                },
+                Violation {
+                    pointer: "/tags".into(),
+                    message: "no item matches the schema in contains".into(),
+                },
+                Violation {
+                    pointer: "/tags/1".into(),
+                    message: "same as item 0".into(),
+                },
+            ]
+        );
+
+        let err = |schema| super::validate(&schema, &json!({"a": {"a": 1}})).unwrap_err();
+        assert_eq!(
+            err(json!({"properties": {"a": {"minimum": 1, "unevaluatedProperties": false}}}))
+                .to_string(),
+            "unsupported schema keyword unevaluatedProperties: /properties/a"
+        );
+        assert_eq!(
+            err(json!({"pattern": "("})).to_string(),
+            "invalid pattern: ("
+        );
+        assert_eq!(
+            err(json!({"$ref": "#/definitions/a", "definitions": {"a": {"$ref": "#"}}}))
+                .to_string(),
+            "$ref refers to itself: #/definitions/a"
+        );
+        // refers to itself for the nested values only
+        let tree = json!({"additionalProperties": {"$ref": "#"}, "type": "object"});
+        assert_eq!(
+            super::validate(&tree, &json!({"a": {"b": 1}})).unwrap(),
+            vec![Violation {
+                pointer: "/a/b".into(),
+                message: "expected type \"object\", found 1".into(),
+            }]
+        );
+    }
+}
@@ -1,9 +1,12 @@
 use comrak::{markdown_to_html, ComrakOptions};
+use crate::ast::{tokenize, Span, TokenKind};
 use crate::context::Context;
+use crate::parser;
 use crate::table_format;
+use crate::yaml::{self, Anchors};
 use failure::{err_msg, Error};
 use serde_json;
-use serde_yaml;
+use std::collections::HashMap;
 use std::str;
 
 #[derive(Debug, PartialEq)]
@@ -13,7 +16,19 @@ pub enum Exec {
     Python,
 }
 
-#[derive(Debug, PartialEq)]
+impl Exec {
+    /// Reads a `!sql` like header token.
+    pub fn from_token(part: &str) -> Result<Exec, Error> {
+        match part.to_lowercase().as_ref() {
+            "!sql" => Ok(Exec::SQL),
+            "!sh" | "!shell" => Ok(Exec::Shell),
+            "!py" | "!python" => Ok(Exec::Python),
+            _ => Err(err_msg(format!("invalid process: {}", part))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Format {
     Text,
     Markdown,
@@ -22,117 +37,305 @@ pub enum Format {
     Table,
 }
 
+impl Format {
+    /// Reads a `~yaml` like header token.
+    pub fn from_token(part: &str) -> Result<Format, Error> {
+        match part.to_lowercase().as_ref() {
+            "~text" => Ok(Format::Text),
+            "~md" | "~markdown" => Ok(Format::Markdown),
+            "~yml" | "~yaml" => Ok(Format::YAML),
+            "~json" => Ok(Format::JSON),
+            "~table" => Ok(Format::Table),
+            _ => Err(err_msg(format!("invalid format: {}", part))),
+        }
+    }
+
+    /// Parses a section body written in this format.
+    pub fn parse(self, body: &str) -> Result<serde_json::Value, Error> {
+        self.parse_with(body, &mut Anchors::new())
+    }
+
+    /// Like `parse`, with the yaml anchors of the earlier bodies of the
+    /// document, adding the ones this body defines.
+    pub fn parse_with(self, body: &str, anchors: &mut Anchors) -> Result<serde_json::Value, Error> {
+        Ok(match self {
+            Format::Text => serde_json::Value::String(body.trim().into()),
+            Format::Markdown => {
+                serde_json::Value::String(markdown_to_html(body, &ComrakOptions::default()))
+            }
+            Format::JSON => serde_json::from_str(body)?,
+            Format::YAML => {
+                if body.trim() == "" {
+                    json!({})
+                } else {
+                    yaml::parse(body, anchors)?
+                }
+            }
+            Format::Table => {
+                serde_json::Value::Array(table_format::csv_to_list_list_string(body.into()))
+            }
+        })
+    }
+}
+
+/// A `if:path` or `unless:path` header token. The section is only used if
+/// the parameter or variable at `path` is (or is not) set to a truthy value.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Condition {
+    If(String),
+    Unless(String),
+}
+
+/// What a `$name` include resolved to.
+pub enum Template {
+    /// A `.json`, `.yml`, `.yaml` or `.txt` template.
+    Value(serde_json::Value),
+    /// Source of a `.graft` template, to be parsed in place of the section.
+    Graft(String),
+}
+
+/// Probes `ctx` for the template `path`, trying each supported extension in
+/// turn. Returns `None` if no such template exists.
+///
+/// A json or yaml template with an `"$extends": "base"` key inherits all keys
+/// of the template `base`, overriding the ones it defines itself.
+pub fn load(path: &str, ctx: &Context) -> Result<Option<Template>, Error> {
+    extend(path, ctx, &mut vec![])
+}
+
+fn extend(path: &str, ctx: &Context, chain: &mut Vec<String>) -> Result<Option<Template>, Error> {
+    if chain.iter().any(|p| p == path) {
+        chain.push(path.to_string());
+        return Err(err_msg(format!("template cycle: {}", chain.join(" -> "))));
+    }
+
+    let value = match probe(path, ctx)? {
+        Some(Template::Value(v)) => v,
+        other => return Ok(other),
+    };
+    let base = match value.get("$extends") {
+        Some(serde_json::Value::String(base)) => base.to_string(),
+        Some(other) => {
+            return Err(err_msg(format!(
+                "$extends is not a string in {}: {}",
+                path, other
+            )))
+        }
+        None => return Ok(Some(Template::Value(value))),
+    };
+
+    chain.push(path.to_string());
+    let mut merged = match extend(&base, ctx, chain)? {
+        Some(Template::Value(serde_json::Value::Object(o))) => o,
+        Some(_) => {
+            return Err(err_msg(format!(
+                "{} extends {}, which is not an object",
+                path, base
+            )))
+        }
+        None => {
+            return Err(err_msg(format!(
+                "{} extends {}, which is not found",
+                path, base
+            )))
+        }
+    };
+    chain.pop();
+
+    // not removing `$extends` up front, as that may reorder the keys
+    if let serde_json::Value::Object(o) = value {
+        for (k, v) in o.into_iter().filter(|(k, _)| k != "$extends") {
+            merged.insert(k, v);
+        }
+    }
+    Ok(Some(Template::Value(serde_json::Value::Object(merged))))
+}
+
+/// The file `load` reads the template `path` from, like `foo.json`.
+pub fn file(path: &str, ctx: &Context) -> Option<String> {
+    ["json", "yml", "yaml", "txt", "graft"]
+        .iter()
+        .map(|ext| format!("{}.{}", path, ext))
+        .find(|file| ctx.lookup(file).is_ok())
+}
+
+fn probe(path: &str, ctx: &Context) -> Result<Option<Template>, Error> {
+    if let Ok(txt) = ctx.lookup(&format!("{}.json", path)) {
+        Ok(Some(Template::Value(serde_json::from_str(&txt)?)))
+    } else if let Ok(txt) = ctx.lookup(&format!("{}.yml", path)) {
+        Ok(Some(Template::Value(yaml::parse(
+            &txt,
+            &mut Anchors::new(),
+        )?)))
+    } else if let Ok(txt) = ctx.lookup(&format!("{}.yaml", path)) {
+        Ok(Some(Template::Value(yaml::parse(
+            &txt,
+            &mut Anchors::new(),
+        )?)))
+    } else if let Ok(txt) = ctx.lookup(&format!("{}.txt", path)) {
+        Ok(Some(Template::Value(serde_json::Value::String(txt))))
+    } else if let Ok(txt) = ctx.lookup(&format!("{}.graft", path)) {
+        Ok(Some(Template::Graft(txt)))
+    } else {
+        Ok(None)
+    }
+}
+
 #[derive(Debug)]
 pub struct Section {
+    /// The header line as written, used when reporting errors.
+    pub header: String,
+    /// Line of the header in the source it was parsed from, 1 based.
+    pub line: usize,
+    /// The `.graft` template the section was parsed from, `None` for the
+    /// document itself.
+    pub source: Option<String>,
+    /// For sections of a `.graft` template, the lines of the sections that
+    /// included it, starting with the one in the document.
+    pub included: Vec<usize>,
+    /// Byte range of the header and body in that source.
+    pub span: Span,
     pub include: Option<String>,
     pub reference: String,
     pub format: Format,
     pub process: Option<Exec>,
+    /// Set by `+merge`: the inline body is deep merged into the template.
+    pub merge: bool,
+    pub conditions: Vec<Condition>,
+    /// Set by `#id`, so the section can be referred to as `{"$ref": "#id"}`.
+    pub id: Option<String>,
+    /// Set by `=id`: the section takes the value of the section marked `#id`.
+    pub alias: Option<String>,
+    /// Set for the sections made from the keys in the body of an include.
+    pub inline: bool,
     pub body: serde_json::Value,
 }
 
 impl Section {
-    pub fn from(header: &str, body: &str, ctx: &Context) -> Result<Vec<Section>, Error> {
+    /// Reads a section. `anchors` are the yaml anchors of the sections
+    /// before it.
+    pub fn from(
+        header: &str,
+        body: &str,
+        ctx: &Context,
+        anchors: &mut Anchors,
+    ) -> Result<Vec<Section>, Error> {
         let mut section = Section {
+            header: ("-- ".to_string() + header.trim().trim_start_matches("--").trim())
+                .trim()
+                .to_string(),
+            line: 0,
+            source: None,
+            included: vec![],
+            span: Span { start: 0, end: 0 },
             include: None,
             reference: "ROOT".into(),
             format: Format::YAML,
             process: None,
+            merge: false,
+            conditions: vec![],
+            id: None,
+            alias: None,
+            inline: false,
             body: serde_json::Value::Null,
         };
         let mut others = vec![];
 
-        for part in header.split_whitespace() {
-            if part == "--" {
-                continue;
-            }
-
-            if part.starts_with("$") {
-                section.include = Some(part[1..].into());
-                continue;
-            }
-
-            if part.starts_with("@") {
-                section.reference = part[1..].into();
-                continue;
-            }
-
-            if part.starts_with("!") {
-                section.process = Some(match part.to_lowercase().as_ref() {
-                    "!sql" => Exec::SQL,
-                    "!sh" | "!shell" => Exec::Shell,
-                    "!py" | "!python" => Exec::Python,
-                    _ => return Err(err_msg(format!("invalid process: {}", part))),
-                });
-                continue;
-            }
-
-            if part.starts_with("~") {
-                section.format = match part.to_lowercase().as_ref() {
-                    "~text" => Format::Text,
-                    "~md" | "~markdown" => Format::Markdown,
-                    "~yml" | "~yaml" => Format::YAML,
-                    "~json" => Format::JSON,
-                    "~table" => Format::Table,
-                    _ => return Err(err_msg(format!("invalid format: {}", part))),
-                };
-                continue;
+        for token in tokenize(header, 0) {
+            let part = &token.text[..];
+            match token.kind {
+                TokenKind::Include => section.include = Some(token.value().into()),
+                TokenKind::Reference => section.reference = token.value().into(),
+                TokenKind::Process => section.process = Some(Exec::from_token(part)?),
+                TokenKind::Format => section.format = Format::from_token(part)?,
+                TokenKind::Mode => match part.to_lowercase().as_ref() {
+                    "+merge" => section.merge = true,
+                    _ => return Err(err_msg(format!("invalid mode: {}", part))),
+                },
+                TokenKind::Id | TokenKind::Alias if token.value().is_empty() => {
+                    return Err(err_msg(format!("invalid id: {}", part)))
+                }
+                TokenKind::Id => section.id = Some(token.value().into()),
+                TokenKind::Alias => section.alias = Some(token.value().into()),
+                TokenKind::If | TokenKind::Unless if token.value().is_empty() => {
+                    return Err(err_msg(format!("invalid condition: {}", part)))
+                }
+                TokenKind::If => section.conditions.push(Condition::If(token.value().into())),
+                TokenKind::Unless => section
+                    .conditions
+                    .push(Condition::Unless(token.value().into())),
+                TokenKind::Unknown => return Err(err_msg(format!("invalid input: {}", part))),
             }
+        }
 
-            return Err(err_msg(format!("invalid input: {}", part)));
+        if section.alias.is_some() && (section.include.is_some() || body.trim() != "") {
+            return Err(err_msg(format!(
+                "section with =id can not have an include or a body: {}",
+                section.header
+            )));
         }
 
         // TODO: handle exec
         section.body = match section.format {
-            Format::Text => serde_json::Value::String(body.trim().into()),
-            Format::Markdown => {
-                serde_json::Value::String(markdown_to_html(body, &ComrakOptions::default()))
-            }
-            Format::JSON => serde_json::from_str(body)?,
-            Format::YAML => {
-                if body.trim() == "" {
-                    json!({})
-                } else {
-                    serde_yaml::from_str(body)?
-                }
-            }
-            Format::Table => {
-                serde_json::Value::Array(table_format::csv_to_list_list_string(body.into()))
+            // rendered by `render`, after variables are interpolated
+            Format::Markdown => serde_json::Value::String(body.into()),
+            // `if:` and `unless:` may remove the section later, so its
+            // anchors are not kept for the sections after it
+            format if !section.conditions.is_empty() => {
+                format.parse_with(body, &mut anchors.clone())?
             }
+            format => format.parse_with(body, anchors)?,
         };
+        if section.merge && !section.body.is_object() {
+            return Err(err_msg(format!(
+                "+merge needs an object body: {}",
+                section.header
+            )));
+        }
 
         let mut drop = false;
         if let Some(ref path) = section.include {
             let obody = section.body.clone();
 
-            if let Ok(txt) = ctx.lookup(&format!("{}.json", path)) {
-                section.body = serde_json::from_str(&txt)?
-            } else if let Ok(txt) = ctx.lookup(&format!("{}.yml", path)) {
-                section.body = serde_yaml::from_str(&txt)?
-            } else if let Ok(txt) = ctx.lookup(&format!("{}.yaml", path)) {
-                section.body = serde_yaml::from_str(&txt)?
-            } else if let Ok(txt) = ctx.lookup(&format!("{}.txt", path)) {
-                section.body = serde_json::Value::String(txt)
-            } else if let Ok(txt) = ctx.lookup(&format!("{}.graft", path)) {
-                // TODO: what to do with body?
-                drop = true;
-                others.extend(Section::parse(&txt, ctx)?)
+            match load(path, ctx)? {
+                Some(Template::Value(v)) => section.body = v,
+                Some(Template::Graft(_)) if section.merge => {
+                    return Err(err_msg(format!(
+                        "+merge needs a json or yaml template: {}",
+                        path
+                    )))
+                }
+                Some(Template::Graft(txt)) => {
+                    // TODO: what to do with body?
+                    drop = true;
+                    for mut other in Section::parse(&txt, ctx)? {
+                        if other.source.is_none() {
+                            other.source = Some(format!("{}.graft", path));
+                        }
+                        other.conditions.extend(section.conditions.iter().cloned());
+                        others.push(other);
+                    }
+                }
+                None if section.merge => {
+                    return Err(err_msg(format!("template not found: {}", path)))
+                }
+                None => {}
             }
 
-            if let serde_json::Value::Object(ref o) = obody {
+            let p = if section.reference == "ROOT" || drop {
+                "".to_string()
+            } else {
+                section.reference.clone() + "/"
+            };
+            if section.merge {
+                let mut appended = vec![];
+                merge(&mut section.body, obody, &mut appended);
+                for (reference, item) in appended {
+                    others.push(section.inline(p.clone() + &reference, item));
+                }
+            } else if let serde_json::Value::Object(ref o) = obody {
                 for (k, v) in o {
-                    let p = if section.reference == "ROOT" || drop {
-                        "".to_string()
-                    } else {
-                        section.reference.clone() + "/"
-                    };
-                    others.push(Section {
-                        include: None,
-                        body: v.clone(),
-                        reference: p + &k,
-                        format: Format::JSON,
-                        process: None,
-                    })
+                    others.push(section.inline(p.clone() + &k, v.clone()))
                 }
             }
         }
@@ -143,20 +346,157 @@ impl Section {
         Ok(others)
     }
 
+    /// A section for a value given in the body of an include.
+    fn inline(&self, reference: String, body: serde_json::Value) -> Section {
+        Section {
+            header: self.header.clone(),
+            line: self.line,
+            source: self.source.clone(),
+            included: self.included.clone(),
+            span: self.span,
+            include: None,
+            reference,
+            format: Format::JSON,
+            process: None,
+            merge: false,
+            conditions: self.conditions.clone(),
+            id: None,
+            alias: None,
+            inline: true,
+            body,
+        }
+    }
+
+    /// Maps the `#id` of each section to its index.
+    pub fn ids(sections: &[Section]) -> Result<HashMap<String, usize>, Error> {
+        let mut ids = HashMap::new();
+        for (idx, section) in sections.iter().enumerate() {
+            if let Some(ref id) = section.id {
+                if let Some(other) = ids.insert(id.to_string(), idx) {
+                    let other = &sections[other];
+                    return Err(err_msg(
+                        if other.included.is_empty() && section.included.is_empty() {
+                            format!(
+                                "duplicate id #{}: lines {} and {}",
+                                id, other.line, section.line
+                            )
+                        } else {
+                            format!(
+                                "duplicate id #{}: {} and {}",
+                                id,
+                                other.location(),
+                                section.location()
+                            )
+                        },
+                    ));
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Where the section is written, like `line 3`, or `part.graft line 1
+    /// (included at line 2)` for a section of a `.graft` template.
+    fn location(&self) -> String {
+        if self.included.is_empty() {
+            return format!("line {}", self.line);
+        }
+        let lines: Vec<String> = self
+            .included
+            .iter()
+            .map(|l| format!("line {}", l))
+            .collect();
+        format!(
+            "{} line {} (included at {})",
+            self.source.as_ref().map_or("template", |s| s.as_str()),
+            self.line,
+            lines.join(", then ")
+        )
+    }
+
     pub fn parse(txt: &str, ctx: &Context) -> Result<Vec<Section>, Error> {
-        let txt = "\n".to_owned() + txt;
         let mut sections = vec![];
-        for part in txt.split("\n--").skip(1) {
-            let part = part.to_owned() + "\n";
-            let split = part.splitn(2, '\n').collect::<Vec<&str>>();
-            let (header, body) = (split[0], split[1]);
-            let body = body.replace("/--", "--");
-            sections.extend(Section::from(header, &body[..], ctx)?);
+        let mut anchors = Anchors::new();
+        for raw in parser::scan(txt) {
+            let mut parsed =
+                Section::from(&raw.header[2..], &raw.unescaped_body(), ctx, &mut anchors)?;
+            for section in parsed.iter_mut() {
+                if section.line != 0 {
+                    // from a `.graft` template, included by this section
+                    section.included.insert(0, raw.line);
+                    continue;
+                }
+                section.line = raw.line;
+                section.span = Span {
+                    start: raw.offset,
+                    end: raw.body_offset + raw.body.len(),
+                };
+            }
+            sections.extend(parsed);
         }
         Ok(sections)
     }
 }
 
+/// Renders the bodies of the Markdown sections, which `Section::parse` keeps
+/// as written, to HTML.
+pub fn render(sections: &mut [Section]) {
+    for section in sections
+        .iter_mut()
+        .filter(|s| s.format == Format::Markdown && s.include.is_none())
+    {
+        if let serde_json::Value::String(ref mut body) = section.body {
+            *body = markdown_to_html(body, &ComrakOptions::default());
+        }
+    }
+}
+
+/// Deep merges `value` into the template `target`. Objects are merged key by
+/// key and arrays are appended to. Values for `$ref` holes are merged into
+/// the hole's `default`, except for list holes, whose items are collected in
+/// `appended` so they can be added as sections instead.
+fn merge(
+    target: &mut serde_json::Value,
+    value: serde_json::Value,
+    appended: &mut Vec<(String, serde_json::Value)>,
+) {
+    let reference = target
+        .get("$ref")
+        .and_then(|r| r.as_str())
+        .map(|r| r.to_string());
+    if let Some(reference) = reference {
+        if reference.ends_with("[]") {
+            match value {
+                serde_json::Value::Array(items) => {
+                    appended.extend(items.into_iter().map(|item| (reference.clone(), item)))
+                }
+                item => appended.push((reference, item)),
+            }
+            return;
+        }
+        let hole = target.as_object_mut().unwrap(); // safe because it has a $ref
+        if let Some(default) = hole.get_mut("default") {
+            return merge(default, value, appended);
+        }
+        hole.insert("default".into(), value);
+        return;
+    }
+
+    match (target, value) {
+        (serde_json::Value::Object(t), serde_json::Value::Object(v)) => {
+            for (k, v) in v {
+                if let Some(tv) = t.get_mut(&k) {
+                    merge(tv, v, appended);
+                    continue;
+                }
+                t.insert(k, v);
+            }
+        }
+        (serde_json::Value::Array(t), serde_json::Value::Array(v)) => t.extend(v),
+        (target, value) => *target = value,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -165,19 +505,89 @@ mod tests {
     #[test]
     fn from() {
         let ctx = StaticContext::new("", "");
+        let from = |header, body| Section::from(header, body, &ctx, &mut Anchors::new());
 
-        let s = &Section::from("-- @ROOT !sql", "foo: bar", &ctx).unwrap()[0];
+        let s = &from("-- @ROOT !sql", "foo: bar").unwrap()[0];
         assert_eq!(s.include, None);
         assert_eq!(s.reference, "ROOT");
         assert_eq!(s.format, Format::YAML);
         assert_eq!(s.process, Some(Exec::SQL));
         assert_eq!(s.body, json!({"foo": "bar"}));
 
-        let s = &Section::from("-- ~text", "yo", &ctx).unwrap()[0];
+        let s = &from("-- ~text", "yo").unwrap()[0];
         assert_eq!(s.include, None);
         assert_eq!(s.reference, "ROOT");
         assert_eq!(s.format, Format::Text);
         assert_eq!(s.process, None);
         assert_eq!(s.body, json!("yo"));
+        assert_eq!(
+            from("-- $page +merge ~text", "blue")
+                .err()
+                .unwrap()
+                .to_string(),
+            "+merge needs an object body: -- $page +merge ~text"
+        );
+
+        let s = &from("-- @beta[] if:env.production unless:flags.beta", "").unwrap()[0];
+        assert_eq!(s.reference, "beta[]");
+        assert_eq!(
+            s.conditions,
+            vec![
+                Condition::If("env.production".into()),
+                Condition::Unless("flags.beta".into())
+            ]
+        );
+        assert!(from("-- @beta[] if:", "").is_err());
+
+        let s = &from("-- @body[] #intro ~md", "hello").unwrap()[0];
+        assert_eq!(s.id, Some("intro".into()));
+        let s = &from("-- @toc[] =intro", "").unwrap()[0];
+        assert_eq!(s.alias, Some("intro".into()));
+        assert!(from("-- @toc[] =intro", "foo: bar").is_err());
+    }
+
+    #[test]
+    fn extends() {
+        let ctx = StaticContext::new(
+            "base.json",
+            r#"{
+                "id": {
+                    "$ref": "id"
+                },
+                "kind": "base"
+            }"#,
+        ).with(
+            "float.json",
+            r#"{
+                "$extends": "base",
+                "kind": "float",
+                "floaters": {
+                    "$ref": "floaters[]"
+                }
+            }"#,
+        ).with("a.json", r#"{"$extends": "b"}"#)
+        .with("b.yml", "$extends: a")
+        .with("c.json", r#"{"$extends": "missing"}"#);
+
+        match load("float", &ctx).unwrap() {
+            Some(Template::Value(v)) => assert_eq!(
+                v,
+                json!({
+                    "id": {"$ref": "id"},
+                    "kind": "float",
+                    "floaters": {"$ref": "floaters[]"}
+                })
+            ),
+            _ => panic!("float not loaded"),
+        }
+
+        assert_eq!(
+            load("a", &ctx).err().unwrap().to_string(),
+            "template cycle: a -> b -> a"
+        );
+        assert_eq!(
+            load("c", &ctx).err().unwrap().to_string(),
+            "c extends missing, which is not found"
+        );
     }
 }
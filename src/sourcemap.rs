@@ -0,0 +1,536 @@
+use crate::ast::Span;
+use crate::context::Context;
+use crate::schema;
+use crate::section::{Format, Section};
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle};
+
+/// Byte ranges of the values of a converted document, by JSON pointer.
+#[derive(Debug, Default, PartialEq)]
+pub struct SourceMap {
+    pub mappings: BTreeMap<String, Mapping>,
+}
+
+/// Where a value was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    /// The template, like `page.json`, `None` for the document.
+    pub file: Option<String>,
+    /// The value itself if it is in a json or yaml template or body,
+    /// otherwise the closest value containing it, or the whole template or
+    /// section.
+    pub span: Span,
+}
+
+impl SourceMap {
+    /// The map as json, like
+    /// `{"version": 1, "mappings": {"/title": {"file": null, "start": 9, "end": 25}}}`.
+    /// Offsets are in bytes.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut mappings = serde_json::Map::new();
+        for (pointer, mapping) in &self.mappings {
+            mappings.insert(
+                pointer.to_string(),
+                json!({
+                    "file": mapping.file,
+                    "start": mapping.span.start,
+                    "end": mapping.span.end,
+                }),
+            );
+        }
+        json!({"version": 1, "mappings": mappings})
+    }
+}
+
+/// Spans of the values in one source, by their pointer in it.
+pub struct Spans {
+    spans: HashMap<String, Span>,
+    /// Span of the whole source.
+    whole: Span,
+}
+
+impl Spans {
+    /// The span of `pointer`, or of the closest value containing it.
+    pub fn find(&self, pointer: &str) -> Span {
+        let mut pointer = pointer;
+        loop {
+            if let Some(span) = self.spans.get(pointer) {
+                return *span;
+            }
+            match pointer.rfind('/') {
+                Some(i) => pointer = &pointer[..i],
+                None => return self.whole,
+            }
+        }
+    }
+}
+
+/// Reads and scans the document and templates, once each.
+pub struct Sources<'a> {
+    txt: &'a str,
+    ctx: &'a Context,
+    templates: HashMap<String, Spans>,
+    /// By source and offset of the section.
+    sections: HashMap<(Option<String>, usize), Spans>,
+}
+
+impl<'a> Sources<'a> {
+    pub fn new(txt: &'a str, ctx: &'a Context) -> Sources<'a> {
+        Sources {
+            txt,
+            ctx,
+            templates: HashMap::new(),
+            sections: HashMap::new(),
+        }
+    }
+
+    /// Spans in the template `file`. Only json and yaml templates are
+    /// scanned.
+    pub fn template(&mut self, file: &str) -> &Spans {
+        let ctx = self.ctx;
+        self.templates.entry(file.to_string()).or_insert_with(|| {
+            let txt = ctx.lookup(file).unwrap_or_default();
+            let spans = if file.ends_with(".json") {
+                scan(&txt, 0)
+            } else if file.ends_with(".yml") || file.ends_with(".yaml") {
+                scan_yaml(&txt, 0)
+            } else {
+                HashMap::new()
+            };
+            Spans {
+                spans,
+                whole: Span {
+                    start: 0,
+                    end: txt.len(),
+                },
+            }
+        })
+    }
+
+    /// Spans in the body of `section`. Only `~json` and `~yaml` bodies are
+    /// scanned.
+    pub fn section(&mut self, section: &Section) -> &Spans {
+        let (txt, ctx) = (self.txt, self.ctx);
+        let key = (section.source.clone(), section.span.start);
+        self.sections.entry(key).or_insert_with(|| {
+            let owned;
+            let txt = match section.source {
+                Some(ref file) => {
+                    owned = ctx.lookup(file).unwrap_or_default();
+                    &owned
+                }
+                None => txt,
+            };
+            let span = section.span;
+            let mut spans = HashMap::new();
+            let scan = match section.format {
+                Format::JSON => scan,
+                Format::YAML => scan_yaml,
+                _ => return Spans { spans, whole: span },
+            };
+            if !section.inline && span.end <= txt.len() {
+                if let Some(i) = txt[span.start..span.end].find('\n') {
+                    let start = span.start + i + 1;
+                    spans = scan(&txt[start..span.end], start);
+                }
+            }
+            Spans { spans, whole: span }
+        })
+    }
+}
+
+/// Finds the span of every value in the json text `txt`, which starts at
+/// `offset` in its source. Returns what was found so far if `txt` is not
+/// valid json.
+pub fn scan(txt: &str, offset: usize) -> HashMap<String, Span> {
+    let mut spans = HashMap::new();
+    let mut scanner = Scanner {
+        txt: txt.as_bytes(),
+        pos: 0,
+    };
+    scanner.value("", offset, &mut spans);
+    spans
+}
+
+struct Scanner<'a> {
+    txt: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.txt.len() && self.txt[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        self.txt.get(self.pos).cloned()
+    }
+
+    /// Skips the value at the current position, recording its span and the
+    /// spans of the values in it.
+    fn value(&mut self, pointer: &str, offset: usize, spans: &mut HashMap<String, Span>) -> bool {
+        let start = match self.peek() {
+            Some(_) => self.pos,
+            None => return false,
+        };
+        let complete = match self.txt[start] {
+            b'{' => self.object(pointer, offset, spans),
+            b'[' => self.array(pointer, offset, spans),
+            b'"' => self.string().is_some(),
+            _ => {
+                while self.pos < self.txt.len() && !b",]} \t\r\n".contains(&self.txt[self.pos]) {
+                    self.pos += 1;
+                }
+                self.pos > start
+            }
+        };
+        if complete {
+            spans.insert(
+                pointer.to_string(),
+                Span {
+                    start: offset + start,
+                    end: offset + self.pos,
+                },
+            );
+        }
+        complete
+    }
+
+    fn object(&mut self, pointer: &str, offset: usize, spans: &mut HashMap<String, Span>) -> bool {
+        self.pos += 1;
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return true;
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return false;
+            }
+            let key = match self.string() {
+                Some(key) => key,
+                None => return false,
+            };
+            if self.peek() != Some(b':') {
+                return false;
+            }
+            self.pos += 1;
+            let pointer = pointer.to_string() + "/" + &schema::escape(&key);
+            if !self.value(&pointer, offset, spans) {
+                return false;
+            }
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return true;
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    fn array(&mut self, pointer: &str, offset: usize, spans: &mut HashMap<String, Span>) -> bool {
+        self.pos += 1;
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return true;
+        }
+        let mut idx = 0;
+        loop {
+            if !self.value(&format!("{}/{}", pointer, idx), offset, spans) {
+                return false;
+            }
+            idx += 1;
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return true;
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    /// Skips the string at the current position, returning it unescaped.
+    fn string(&mut self) -> Option<String> {
+        let start = self.pos;
+        self.pos += 1;
+        while self.pos < self.txt.len() {
+            match self.txt[self.pos] {
+                b'\\' => self.pos += 2,
+                b'"' => {
+                    self.pos += 1;
+                    // safe because both ends are ascii quotes in a str
+                    let raw = std::str::from_utf8(&self.txt[start..self.pos]).unwrap();
+                    return serde_json::from_str(raw).ok();
+                }
+                _ => self.pos += 1,
+            }
+        }
+        None
+    }
+}
+
+/// Like `scan`, for the yaml text `txt`. Values that are aliases span the
+/// alias, and the spans stop at the first error, like an alias to an anchor
+/// of an earlier body.
+pub fn scan_yaml(txt: &str, offset: usize) -> HashMap<String, Span> {
+    let mut scanner = YamlScanner {
+        txt,
+        offset,
+        bytes: txt.char_indices().map(|(i, _)| i).collect(),
+        spans: HashMap::new(),
+        open: vec![],
+        scalar: None,
+    };
+    let _ = Parser::new(txt.chars()).load(&mut scanner, false);
+    scanner.spans
+}
+
+struct YamlScanner<'a> {
+    txt: &'a str,
+    offset: usize,
+    /// Byte offset of each char, as markers count chars.
+    bytes: Vec<usize>,
+    spans: HashMap<String, Span>,
+    open: Vec<Collection>,
+    /// A scalar that ends before the next event, with its pointer, start
+    /// and style.
+    scalar: Option<(Option<String>, usize, TScalarStyle)>,
+}
+
+/// A map or list that has started and not ended yet.
+struct Collection {
+    /// `None` for a map key, or a value in one.
+    pointer: Option<String>,
+    start: usize,
+    /// End of the last value in it.
+    end: usize,
+    flow: bool,
+    map: bool,
+    is_key: bool,
+    /// In a map, the key of the next value, `None` while the key is still
+    /// to come. It is `Some(None)` if the key is not a string.
+    key: Option<Option<String>>,
+    /// Number of items, or keys, so far.
+    idx: usize,
+}
+
+impl<'a> YamlScanner<'a> {
+    /// The pointer of the value starting now at `at`, and whether it is a
+    /// map key.
+    fn enter(&mut self, at: usize) -> (Option<String>, bool) {
+        let parent = match self.open.last_mut() {
+            Some(parent) => parent,
+            None => return (Some(String::new()), false),
+        };
+        if parent.map {
+            return match parent.key.take() {
+                None => {
+                    // the events of block maps start at the first `:`
+                    if parent.idx == 0 && !parent.flow {
+                        parent.start = at;
+                    }
+                    parent.idx += 1;
+                    (None, true)
+                }
+                Some(key) => match (&parent.pointer, key) {
+                    (Some(p), Some(k)) => (Some(p.clone() + "/" + &schema::escape(&k)), false),
+                    _ => (None, false),
+                },
+            };
+        }
+        parent.idx += 1;
+        let pointer = parent.pointer.as_ref();
+        (pointer.map(|p| format!("{}/{}", p, parent.idx - 1)), false)
+    }
+
+    fn key(&mut self, key: Option<String>) {
+        if let Some(parent) = self.open.last_mut() {
+            parent.key = Some(key);
+        }
+    }
+
+    fn leave(&mut self, pointer: Option<String>, start: usize, end: usize) {
+        if let Some(pointer) = pointer {
+            let span = Span {
+                start: self.offset + start,
+                end: self.offset + end,
+            };
+            self.spans.insert(pointer, span);
+        }
+        if let Some(parent) = self.open.last_mut() {
+            parent.end = end;
+        }
+    }
+
+    /// The end of the scalar at `start`, given that the next event is at
+    /// `next`.
+    fn scalar_end(&self, start: usize, next: usize, style: TScalarStyle) -> usize {
+        let mut text = &self.txt[start..next.max(start)];
+        let quote = match style {
+            TScalarStyle::SingleQuoted => Some('\''),
+            TScalarStyle::DoubleQuoted => Some('"'),
+            _ => None,
+        };
+        if let Some(quote) = quote {
+            let mut chars = text.char_indices().skip_while(|&(_, c)| c != quote).skip(1);
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' if quote == '"' => {
+                        chars.next();
+                    }
+                    // `''` is a quote in a single quoted string
+                    '\'' if text[i + 1..].starts_with('\'') => {
+                        chars.next();
+                    }
+                    c if c == quote => return start + i + 1,
+                    _ => {}
+                }
+            }
+            return start + text.len();
+        }
+        // what is left before the next event is space, comments and
+        // punctuation, like the `-` of the next item of a list
+        loop {
+            text = text.trim_end();
+            let last = match text.rfind('\n') {
+                Some(i) => i,
+                None => break,
+            };
+            match text[last + 1..].trim_start() {
+                "-" | "?" => text = &text[..last],
+                line if line.starts_with('#') => text = &text[..last],
+                _ => break,
+            }
+        }
+        if style == TScalarStyle::Plain {
+            if let Some(i) = text.find(" #").or_else(|| text.find("\t#")) {
+                text = &text[..i];
+            }
+            if self.open.last().map_or(false, |c| c.flow) {
+                text = text.trim_end().trim_end_matches(',');
+            }
+            text = text.trim_end();
+        }
+        start + text.len()
+    }
+}
+
+impl<'a> MarkedEventReceiver for YamlScanner<'a> {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let at = self.bytes.get(mark.index()).cloned().unwrap_or(self.txt.len());
+        if let Some((pointer, start, style)) = self.scalar.take() {
+            let end = self.scalar_end(start, at, style);
+            self.leave(pointer, start, end);
+        }
+        match event {
+            Event::Scalar(value, style, ..) => match self.enter(at) {
+                (_, true) => self.key(Some(value)),
+                (pointer, false) => self.scalar = Some((pointer, at, style)),
+            },
+            Event::Alias(_) => match self.enter(at) {
+                (_, true) => self.key(None),
+                (pointer, false) => {
+                    let len = self.txt[at..]
+                        .find(|c: char| c.is_whitespace() || ",]}".contains(c))
+                        .unwrap_or(self.txt.len() - at);
+                    self.leave(pointer, at, at + len);
+                }
+            },
+            Event::MappingStart(_) | Event::SequenceStart(_) => {
+                let (pointer, is_key) = self.enter(at);
+                self.open.push(Collection {
+                    pointer,
+                    start: at,
+                    end: at,
+                    flow: self.txt[at..].starts_with(|c| c == '{' || c == '['),
+                    map: match event {
+                        Event::MappingStart(_) => true,
+                        _ => false,
+                    },
+                    is_key,
+                    key: None,
+                    idx: 0,
+                });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                let collection = match self.open.pop() {
+                    Some(collection) => collection,
+                    None => return,
+                };
+                let end = if collection.flow {
+                    at + 1
+                } else {
+                    collection.end
+                };
+                if collection.is_key {
+                    self.key(None);
+                } else {
+                    self.leave(collection.pointer, collection.start, end);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::Span;
+
+    #[test]
+    fn scan() {
+        let txt = r#"{"a": [1, {"b/c": "x\"y"}], "d": null}"#;
+        let spans = super::scan(txt, 10);
+        let at = |pointer: &str| {
+            let span: Span = spans[pointer];
+            &txt[span.start - 10..span.end - 10]
+        };
+        assert_eq!(at(""), txt);
+        assert_eq!(at("/a"), r#"[1, {"b/c": "x\"y"}]"#);
+        assert_eq!(at("/a/0"), "1");
+        assert_eq!(at("/a/1/b~1c"), r#""x\"y""#);
+        assert_eq!(at("/d"), "null");
+        assert_eq!(spans.len(), 6);
+
+        let spans = super::scan(r#"{"a": 1, "b": "#, 0);
+        assert_eq!(spans["/a"], Span { start: 6, end: 7 });
+        assert!(!spans.contains_key(""));
+    }
+
+    #[test]
+    fn scan_yaml() {
+        let txt = concat!(
+            "title: \u{e9}t\u{e9}  # comment\n",
+            "tags: [a, 'b''c', {d: \"e\\\"\"}]\n",
+            "? [complex]\n",
+            ": x\n",
+            "list:\n",
+            "- &one 1\n",
+            "- *one\n",
+            "- |\n",
+            "  text\n",
+            "\n",
+            "# the end\n",
+        );
+        let spans = super::scan_yaml(txt, 10);
+        let at = |pointer: &str| {
+            let span: Span = spans[pointer];
+            &txt[span.start - 10..span.end - 10]
+        };
+        assert_eq!(at(""), &txt[..txt.find("\n\n").unwrap()]);
+        assert_eq!(at("/title"), "\u{e9}t\u{e9}");
+        assert_eq!(at("/tags"), r#"[a, 'b''c', {d: "e\""}]"#);
+        assert_eq!(at("/tags/0"), "a");
+        assert_eq!(at("/tags/1"), "'b''c'");
+        assert_eq!(at("/tags/2/d"), r#""e\"""#);
+        assert_eq!(at("/list"), "- &one 1\n- *one\n- |\n  text");
+        assert_eq!(at("/list/0"), "1");
+        assert_eq!(at("/list/1"), "*one");
+        assert_eq!(at("/list/2"), "text");
+        assert_eq!(spans.len(), 11);
+    }
+}
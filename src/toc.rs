@@ -0,0 +1,109 @@
+use crate::section::{Format, Section};
+use crate::transform::slug;
+use serde_json;
+use std::collections::HashSet;
+
+struct Heading {
+    level: usize,
+    id: String,
+    title: String,
+}
+
+/// Gives every heading in the Markdown sections an `id` attribute, unique
+/// across the document, and returns the table of contents built from them.
+///
+/// The table of contents is a list of `{"id", "title", "level", "children"}`
+/// objects, where a heading's children are the deeper headings following it.
+pub fn anchor(sections: &mut [Section]) -> serde_json::Value {
+    let mut headings = vec![];
+    let mut seen = HashSet::new();
+    for section in sections.iter_mut().filter(|s| s.format == Format::Markdown) {
+        if let serde_json::Value::String(ref mut html) = section.body {
+            *html = anchor_html(html, &mut headings, &mut seen);
+        }
+    }
+    serde_json::Value::Array(nest(&headings))
+}
+
+fn anchor_html(html: &str, headings: &mut Vec<Heading>, seen: &mut HashSet<String>) -> String {
+    let mut out = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("<h") {
+        let level = match rest[start + 2..]
+            .chars()
+            .next()
+            .and_then(|c| c.to_digit(10))
+        {
+            Some(level) if level >= 1 && level <= 6 && rest[start + 3..].starts_with('>') => {
+                level as usize
+            }
+            _ => {
+                out += &rest[..start + 2];
+                rest = &rest[start + 2..];
+                continue;
+            }
+        };
+        let close = format!("</h{}>", level);
+        let end = match rest[start + 4..].find(&close) {
+            Some(end) => start + 4 + end,
+            None => break,
+        };
+        let title = text(&rest[start + 4..end]);
+
+        let mut base = slug(&title);
+        if base.is_empty() {
+            base = "section".to_string();
+        }
+        let mut id = base.clone();
+        let mut n = 0;
+        while !seen.insert(id.clone()) {
+            n += 1;
+            id = format!("{}-{}", base, n);
+        }
+
+        out += &rest[..start];
+        out += &format!("<h{} id=\"{}\">", level, id);
+        out += &rest[start + 4..end];
+        rest = &rest[end..];
+        headings.push(Heading { level, id, title });
+    }
+    out + rest
+}
+
+/// The text of a piece of html, without tags.
+fn text(html: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+fn nest(headings: &[Heading]) -> Vec<serde_json::Value> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < headings.len() {
+        let h = &headings[i];
+        let end = headings[i + 1..]
+            .iter()
+            .position(|o| o.level <= h.level)
+            .map_or(headings.len(), |p| i + 1 + p);
+        out.push(json!({
+            "id": h.id,
+            "title": h.title,
+            "level": h.level,
+            "children": nest(&headings[i + 1..end]),
+        }));
+        i = end;
+    }
+    out
+}
@@ -0,0 +1,83 @@
+use chrono::{DateTime, NaiveDate};
+use failure::{err_msg, Error};
+use serde_json;
+use std::fmt::Write;
+
+/// Applies the `"transform"` of a template hole to the value it resolved to.
+///
+/// Supported transforms are `slug`, `upper`, `lower` and `date:<format>`,
+/// which reformats a `2018-11-05` or RFC 3339 date using `strftime` syntax.
+pub fn apply(transform: &str, value: serde_json::Value) -> Result<serde_json::Value, Error> {
+    let s = match value {
+        serde_json::Value::String(s) => s,
+        serde_json::Value::Null => return Ok(value),
+        _ => {
+            return Err(err_msg(format!(
+                "transform {} needs a string, found {}",
+                transform, value
+            )))
+        }
+    };
+    let out = match transform {
+        "slug" => slug(&s),
+        "upper" => s.to_uppercase(),
+        "lower" => s.to_lowercase(),
+        t if t.starts_with("date:") => date(&s, &t[5..])?,
+        _ => return Err(err_msg(format!("unknown transform: {}", transform))),
+    };
+    Ok(serde_json::Value::String(out))
+}
+
+/// Lowercases `s` and joins its alphanumeric runs with `-`.
+pub fn slug(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            out.push(c);
+        } else if !out.is_empty() && !out.ends_with('-') {
+            out.push('-');
+        }
+    }
+    out.trim_end_matches('-').to_string()
+}
+
+fn date(s: &str, format: &str) -> Result<String, Error> {
+    let mut out = String::new();
+    let written = if let Ok(d) = DateTime::parse_from_rfc3339(s) {
+        write!(out, "{}", d.format(format))
+    } else if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        write!(out, "{}", d.format(format))
+    } else {
+        return Err(err_msg(format!("not a date: {}", s)));
+    };
+    written.map_err(|_| err_msg(format!("invalid date format: {}", format)))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn apply() {
+        let t = |transform: &str, value: serde_json::Value| {
+            super::apply(transform, value).map_err(|e| e.to_string())
+        };
+        assert_eq!(t("slug", json!("Hello, World!")), Ok(json!("hello-world")));
+        assert_eq!(t("upper", json!("yo")), Ok(json!("YO")));
+        assert_eq!(t("lower", json!("YO")), Ok(json!("yo")));
+        assert_eq!(t("date:%Y", json!("2018-11-05")), Ok(json!("2018")));
+        assert_eq!(
+            t("date:%d %b", json!("2018-11-05T10:00:00+05:30")),
+            Ok(json!("05 Nov"))
+        );
+        assert_eq!(t("upper", json!(null)), Ok(json!(null)));
+        assert_eq!(
+            t("upper", json!(1)),
+            Err("transform upper needs a string, found 1".into())
+        );
+        assert_eq!(t("date:%Y", json!("soon")), Err("not a date: soon".into()));
+        assert_eq!(
+            t("title", json!("a")),
+            Err("unknown transform: title".into())
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use crate::section::{Condition, Format, Section};
+use failure::{err_msg, Error};
+use serde_json;
+use serde_yaml;
+
+/// Parameters supplied by the caller of a conversion, for example to render
+/// the same document for staging and production. They are available to
+/// interpolation next to, and taking precedence over, `@vars`.
+#[derive(Debug, Clone)]
+pub struct Params {
+    pub values: serde_json::Map<String, serde_json::Value>,
+    /// If set, which is the default, referring to a variable that is not
+    /// defined is an error, otherwise it is treated as `null`.
+    pub strict: bool,
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params {
+            values: serde_json::Map::new(),
+            strict: true,
+        }
+    }
+}
+
+impl Params {
+    pub fn new() -> Params {
+        Params::default()
+    }
+
+    /// Sets the parameter `key`, which may be dotted like `site.name`.
+    pub fn with(mut self, key: &str, value: serde_json::Value) -> Params {
+        let mut target = &mut self.values;
+        let mut keys: Vec<&str> = key.split('.').collect();
+        let last = keys.pop().unwrap(); // safe because split yields at least one item
+        for k in keys {
+            let entry = target.entry(k).or_insert_with(|| json!({}));
+            if !entry.is_object() {
+                *entry = json!({});
+            }
+            target = entry.as_object_mut().unwrap(); // safe because we have already checked
+        }
+        target.insert(last.to_string(), value);
+        self
+    }
+
+    /// Sets a parameter from a `key=value` string. The value is read as a
+    /// yaml scalar, so `true` and `10` are a boolean and a number.
+    pub fn parse(self, arg: &str) -> Result<Params, Error> {
+        let mut split = arg.splitn(2, '=');
+        let key = split.next().unwrap_or("").trim();
+        let value = split
+            .next()
+            .ok_or_else(|| err_msg(format!("expected key=value: {}", arg)))?;
+        if key.is_empty() {
+            return Err(err_msg(format!("expected key=value: {}", arg)));
+        }
+        let value = match serde_yaml::from_str(value) {
+            Ok(serde_json::Value::Null) | Err(_) => serde_json::Value::String(value.into()),
+            Ok(v) => v,
+        };
+        Ok(self.with(key, value))
+    }
+
+    /// Sets `env.<name>` to `true`.
+    pub fn env(self, name: &str) -> Params {
+        self.with(&format!("env.{}", name), serde_json::Value::Bool(true))
+    }
+
+    pub fn strict(mut self, strict: bool) -> Params {
+        self.strict = strict;
+        self
+    }
+}
+
+/// Collects the `-- @vars` sections, removing them from `sections`, and
+/// replaces every `${name.path}` in the remaining section bodies (including
+/// values that came from templates) with the value of that variable.
+///
+/// A string that is exactly `${name}` is replaced by the variable's value
+/// as is, so numbers, lists and objects can be shared too. Like `/--` in
+/// bodies, `/${` at the start of a string or after whitespace is a literal
+/// `${`; elsewhere, as in `${base}/${page}`, the slash is kept as is. In
+/// `~md` bodies, fenced code blocks and code spans are left as they are.
+///
+/// Sections with an `if:` or `unless:` condition that does not hold are
+/// removed first, along with the sections below them, like `@banner/title`
+/// after a disabled `@banner`. The conditions of `@vars` sections see the
+/// parameters and the variables set above them.
+pub fn interpolate(sections: &mut Vec<Section>, params: &Params) -> Result<(), Error> {
+    let mut vars = serde_json::Map::new();
+    for section in sections.iter().filter(|s| s.reference == "vars") {
+        let mut seen = vars.clone();
+        overlay(&mut seen, &params.values);
+        let seen = serde_json::Value::Object(seen);
+        if !section.conditions.iter().all(|c| holds(c, &seen)) {
+            continue;
+        }
+        match section.body {
+            serde_json::Value::Object(ref o) => vars.extend(o.clone()),
+            _ => {
+                return Err(err_msg(format!(
+                    "section `{}` (line {}): vars must be an object",
+                    section.header, section.line
+                )))
+            }
+        }
+    }
+    sections.retain(|s| s.reference != "vars");
+    overlay(&mut vars, &params.values);
+
+    let vars = serde_json::Value::Object(vars);
+    let mut disabled: Option<String> = None;
+    sections.retain(|section| {
+        if let Some(ref parent) = disabled {
+            if section.reference.starts_with(&(parent.to_string() + "/")) {
+                return false;
+            }
+        }
+        disabled = None;
+        if section.conditions.iter().all(|c| holds(c, &vars)) {
+            return true;
+        }
+        disabled = Some(section.reference.clone());
+        false
+    });
+
+    for section in sections.iter_mut() {
+        let markdown = match section.body {
+            serde_json::Value::String(ref s) if section.format == Format::Markdown => {
+                Some(markdown(s, &vars, params.strict))
+            }
+            _ => None,
+        };
+        let result = match markdown {
+            Some(Ok(s)) => {
+                section.body = serde_json::Value::String(s);
+                Ok(())
+            }
+            Some(Err(e)) => Err(e),
+            None => walk(&mut section.body, &vars, params.strict),
+        };
+        result.map_err(|e| {
+            err_msg(format!(
+                "section `{}` (line {}): {}",
+                section.header, section.line, e
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+fn holds(condition: &Condition, vars: &serde_json::Value) -> bool {
+    let (path, expected) = match condition {
+        Condition::If(path) => (path, true),
+        Condition::Unless(path) => (path, false),
+    };
+    let truthy = match lookup(vars, path) {
+        Ok(serde_json::Value::Null) | Err(_) => false,
+        Ok(serde_json::Value::Bool(b)) => *b,
+        Ok(serde_json::Value::String(s)) => !s.is_empty(),
+        Ok(serde_json::Value::Number(n)) => n.as_f64() != Some(0.0),
+        Ok(_) => true,
+    };
+    truthy == expected
+}
+
+fn overlay(
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    values: &serde_json::Map<String, serde_json::Value>,
+) {
+    for (k, v) in values {
+        if let (Some(serde_json::Value::Object(t)), serde_json::Value::Object(v)) =
+            (target.get_mut(k), v)
+        {
+            overlay(t, v);
+            continue;
+        }
+        target.insert(k.to_string(), v.clone());
+    }
+}
+
+fn walk(
+    value: &mut serde_json::Value,
+    vars: &serde_json::Value,
+    strict: bool,
+) -> Result<(), Error> {
+    match value {
+        serde_json::Value::String(s) => {
+            if s.contains("${") {
+                *value = substitute(s, vars, strict)?;
+            }
+        }
+        serde_json::Value::Array(a) => {
+            for item in a {
+                walk(item, vars, strict)?;
+            }
+        }
+        serde_json::Value::Object(o) => {
+            for (_, item) in o {
+                walk(item, vars, strict)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn substitute(s: &str, vars: &serde_json::Value, strict: bool) -> Result<serde_json::Value, Error> {
+    let resolve = |name: &str| match lookup(vars, name) {
+        Ok(v) => Ok(v.clone()),
+        Err(_) if !strict => Ok(serde_json::Value::Null),
+        Err(e) => Err(e),
+    };
+    if s.starts_with("${") && s.find('}') == Some(s.len() - 1) {
+        return resolve(&s[2..s.len() - 1]);
+    }
+
+    let mut out = String::new();
+    let mut pos = 0;
+    while let Some(found) = s[pos..].find("${") {
+        let start = pos + found;
+        let escaped = s[..start].ends_with('/')
+            && s[..start - 1]
+                .chars()
+                .last()
+                .map_or(true, char::is_whitespace);
+        if escaped {
+            out += &s[pos..start - 1];
+            out += "${";
+            pos = start + 2;
+            continue;
+        }
+        out += &s[pos..start];
+        let end = start
+            + s[start..]
+                .find('}')
+                .ok_or_else(|| err_msg(format!("unterminated variable: {}", &s[start..])))?;
+        out += &text(resolve(&s[start + 2..end])?, &s[start..=end])?;
+        pos = end + 1;
+    }
+    out += &s[pos..];
+    Ok(serde_json::Value::String(out))
+}
+
+/// The value of the variable `name`, written like `${name}`, as text.
+fn text(value: serde_json::Value, name: &str) -> Result<String, Error> {
+    match value {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Null => Ok("".to_string()),
+        v @ serde_json::Value::Object(_) | v @ serde_json::Value::Array(_) => Err(err_msg(
+            format!("can not interpolate {} into text: {}", name, v),
+        )),
+        v => Ok(v.to_string()),
+    }
+}
+
+/// Like `substitute` for the markdown `s`, leaving fenced code blocks and
+/// code spans as they are.
+fn markdown(s: &str, vars: &serde_json::Value, strict: bool) -> Result<String, Error> {
+    let mut out = String::new();
+    let mut prose = String::new();
+    // the backticks or tildes that opened the code block we are in
+    let mut fence: Option<String> = None;
+    let lines: Vec<&str> = s.split('\n').collect();
+    for (idx, line) in lines.iter().enumerate() {
+        let newline = if idx + 1 < lines.len() { "\n" } else { "" };
+        let trimmed = line.trim_start();
+        let indented = line.len() - trimmed.len() > 3;
+        let marker: String = match trimmed.chars().next() {
+            Some(c) if (c == '`' || c == '~') && !indented => {
+                trimmed.chars().take_while(|&m| m == c).collect()
+            }
+            _ => String::new(),
+        };
+        let closes = fence.as_ref().map_or(false, |f| {
+            marker.starts_with(f.as_str()) && trimmed.trim_end().len() == marker.len()
+        });
+        if fence.is_some() {
+            out += line;
+            out += newline;
+            if closes {
+                fence = None;
+            }
+        } else if marker.len() >= 3 {
+            out += &code_spans(&prose, vars, strict)?;
+            prose.clear();
+            out += line;
+            out += newline;
+            fence = Some(marker);
+        } else {
+            prose += line;
+            prose += newline;
+        }
+    }
+    out += &code_spans(&prose, vars, strict)?;
+    Ok(out)
+}
+
+/// Substitutes the variables in `s` outside of its code spans.
+fn code_spans(s: &str, vars: &serde_json::Value, strict: bool) -> Result<String, Error> {
+    let ticks = |from: usize| s[from..].chars().take_while(|&c| c == '`').count();
+    let prose = |t: &str| {
+        if t.contains("${") {
+            text(substitute(t, vars, strict)?, t)
+        } else {
+            Ok(t.to_string())
+        }
+    };
+    let mut out = String::new();
+    let mut plain = 0;
+    let mut pos = 0;
+    while let Some(found) = s[pos..].find('`') {
+        let start = pos + found;
+        let n = ticks(start);
+        pos = start + n;
+        // a span ends at the next run of as many backticks
+        let mut search = pos;
+        while let Some(found) = s[search..].find('`') {
+            let close = search + found;
+            let m = ticks(close);
+            if m == n {
+                out += &prose(&s[plain..start])?;
+                out += &s[start..close + m];
+                plain = close + m;
+                pos = plain;
+                break;
+            }
+            search = close + m;
+        }
+    }
+    out += &prose(&s[plain..])?;
+    Ok(out)
+}
+
+/// Looks up a dotted variable name like `site.links.0`.
+pub fn lookup<'a>(vars: &'a serde_json::Value, name: &str) -> Result<&'a serde_json::Value, Error> {
+    let name = name.trim();
+    let mut value = vars;
+    for key in name.split('.') {
+        let next = match value {
+            serde_json::Value::Object(o) => o.get(key),
+            serde_json::Value::Array(a) => key.parse::<usize>().ok().and_then(|i| a.get(i)),
+            _ => None,
+        };
+        value = next.ok_or_else(|| err_msg(format!("undefined variable: {}", name)))?;
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Params;
+    use crate::context::StaticContext;
+    use crate::convert::{convert, convert_with};
+    use textwrap::dedent as d;
+
+    #[test]
+    fn interpolate() {
+        let ctx = StaticContext::new(
+            "page.json",
+            r#"{
+                "title": {
+                    "$ref": "title",
+                    "default": "${site.name}"
+                },
+                "links": {
+                    "$ref": "links",
+                    "default": "${site.links}"
+                },
+                "body": {
+                    "$ref": "body"
+                }
+            }"#,
+        );
+
+        let txt = d(r#"
+            -- @vars
+            site:
+              name: Acme & Co
+              url: http://acme.test
+              links: [a, b]
+            -- $page
+            -- @body ~md
+            welcome to *${site.name}*, see ${site.links.1} /${not.a.var}
+            and [home](${site.url}/home), not `${code}`
+            ```sh
+            echo ${HOME}
+            ```"#);
+        assert_eq!(
+            convert(&txt, &ctx).unwrap(),
+            json!({
+                "title": "Acme & Co",
+                "links": ["a", "b"],
+                "body": "<p>welcome to <em>Acme &amp; Co</em>, see b ${not.a.var}\nand <a href=\"http://acme.test/home\">home</a>, not <code>${code}</code></p>\n<pre><code class=\"language-sh\">echo ${HOME}\n</code></pre>\n",
+            })
+        );
+
+        let txt = d(r#"
+            -- @ROOT ~text
+            ${site.name}"#);
+        assert_eq!(
+            convert(&txt, &ctx).unwrap_err().to_string(),
+            "section `-- @ROOT ~text` (line 2): undefined variable: site.name"
+        );
+    }
+
+    #[test]
+    fn params() {
+        let ctx = StaticContext::new(
+            "page.json",
+            r#"{
+                "url": {
+                    "$ref": "url",
+                    "default": "${site.url}/${page}"
+                },
+                "debug": {
+                    "$ref": "debug",
+                    "default": "${debug}"
+                }
+            }"#,
+        );
+
+        let txt = d(r#"
+            -- @vars
+            site:
+              url: http://localhost
+            page: about
+            -- $page"#);
+
+        let params = Params::new()
+            .with("site.url", json!("https://example.com"))
+            .parse("debug=true")
+            .unwrap();
+        assert_eq!(
+            convert_with(&txt, &ctx, &params).unwrap(),
+            json!({"url": "https://example.com/about", "debug": true})
+        );
+
+        assert_eq!(
+            convert_with(&txt, &ctx, &Params::new().strict(false)).unwrap(),
+            json!({"url": "http://localhost/about", "debug": null})
+        );
+        assert_eq!(
+            convert_with(&txt, &ctx, &Params::new())
+                .unwrap_err()
+                .to_string(),
+            "section `-- $page` (line 6): undefined variable: debug"
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        let ctx = StaticContext::new(
+            "page.json",
+            r#"{
+                "banner": {
+                    "$ref": "banner",
+                    "default": null
+                },
+                "items": {
+                    "$ref": "items[]"
+                }
+            }"#,
+        ).with("banner.json", r#"{"text": {"$ref": "text"}}"#);
+
+        let txt = d(r#"
+            -- @vars
+            flags:
+              beta: false
+            -- @vars if:env.production
+            flags:
+              beta: true
+            -- $page
+            -- @banner $banner if:env.production
+            -- @banner/text ~text
+            ${missing}
+            -- @items[]
+            id: a
+            -- @items[] unless:flags.beta
+            id: b
+            -- @items[] if:flags.beta
+            id: c
+            -- @items[] if:env.production unless:flags.beta
+            id: d"#);
+        assert_eq!(
+            convert_with(&txt, &ctx, &Params::new()).unwrap(),
+            json!({"banner": null, "items": [{"id": "a"}, {"id": "b"}]})
+        );
+        assert_eq!(
+            convert_with(
+                &txt,
+                &ctx,
+                &Params::new()
+                    .env("production")
+                    .parse("flags.beta=yes")
+                    .unwrap()
+                    .strict(false)
+            )
+            .unwrap(),
+            json!({"banner": {"text": null}, "items": [{"id": "a"}, {"id": "c"}]})
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::schema;
+use failure::{err_msg, Error};
+use serde_json;
+use serde_yaml;
+use yaml_rust::scanner::{Scanner, Token, TokenType};
+
+const ANCHORS: &str = "__graft_anchors";
+const BODY: &str = "__graft_body";
+const ANCHOR: &str = "__graft_anchor";
+
+/// Anchors defined in the yaml bodies of a document so far, so later bodies
+/// can use them as aliases.
+#[derive(Clone, Debug, Default)]
+pub struct Anchors {
+    values: Vec<(String, serde_json::Value)>,
+}
+
+impl Anchors {
+    pub fn new() -> Anchors {
+        Anchors::default()
+    }
+
+    /// The anchors as a yaml list, then `body` under a key, then `tail`.
+    /// Directives and the `---` that starts the body are blanked out, as
+    /// they can only come first in a document.
+    fn wrap(&self, body: &str, tail: &str) -> String {
+        let mut out = format!("{}:\n", ANCHORS);
+        for (name, value) in &self.values {
+            // json is valid yaml, in flow style
+            out += &format!("  - &{} {}\n", name, value);
+        }
+        out += &format!("{}:\n", BODY);
+        let mut started = false;
+        for line in body.lines() {
+            out += "    ";
+            if started {
+                out += line;
+            } else if line.starts_with('%') {
+                out += &" ".repeat(line.len());
+            } else if line == "---" || line.starts_with("--- ") {
+                out += "   ";
+                out += &line[3..];
+                started = true;
+            } else {
+                out += line;
+                started = !line.trim().is_empty() && !line.trim_start().starts_with('#');
+            }
+            out += "\n";
+        }
+        out + tail
+    }
+
+    /// Parses `body` wrapped after the anchors, with error positions
+    /// relative to `body` itself.
+    fn parse(&self, body: &str, tail: &str) -> Result<serde_yaml::Value, Error> {
+        serde_yaml::from_str(&self.wrap(body, tail)).map_err(|e| {
+            let message = e.to_string();
+            let location = match e.location() {
+                Some(location) => location,
+                None => return err_msg(message),
+            };
+            // the anchors, one per line, between the two keys
+            let lines = self.values.len() + 2;
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            if location.line() <= lines || !message.ends_with(&suffix) {
+                return err_msg(message);
+            }
+            let message = &message[..message.len() - suffix.len()];
+            err_msg(format!(
+                "{} at line {} column {}",
+                message,
+                location.line() - lines,
+                location.column().saturating_sub(4).max(1)
+            ))
+        })
+    }
+
+    /// Records the anchors `body` defines, replacing earlier ones with the
+    /// same names.
+    fn collect(&mut self, body: &str) -> Result<(), Error> {
+        let names = names(body);
+        if names.is_empty() {
+            return Ok(());
+        }
+        let mut tail = String::new();
+        for (i, name) in names.iter().enumerate() {
+            tail += &format!("{}_{}: *{}\n", ANCHOR, i, name);
+        }
+        let wrapped = self.parse(body, &tail)?;
+        for (i, name) in names.into_iter().enumerate() {
+            // safe because the tail has a key for each name
+            let value = wrapped.get(format!("{}_{}", ANCHOR, i)).cloned().unwrap();
+            self.values.retain(|(n, _)| *n != name);
+            self.values.push((name, convert(value, "")?));
+        }
+        Ok(())
+    }
+}
+
+/// Parses a yaml body into json, with these rules:
+///
+/// - Integers stay integers and floats stay floats, so `1.0` is `1.0`.
+///   `.nan` and `.inf` are an error, as json has no such numbers.
+/// - Map keys must be strings; `1: a` or `true: a` are an error rather than
+///   silently becoming `"1"` or `"true"`, quote such keys.
+/// - Tags are not kept: `!!str 1` is the string `"1"` and `!custom x` is
+///   just `"x"`.
+/// - Aliases may refer to anchors in the same body or in an earlier yaml
+///   body of the document, like `*base` after `base: &base {size: 10}`.
+/// - `<<: *base` merges the keys of the map `base`, or of each map in a
+///   list, into the map it is in. Keys the map has itself win.
+pub fn parse(body: &str, anchors: &mut Anchors) -> Result<serde_json::Value, Error> {
+    let value = if anchors.values.is_empty() {
+        serde_yaml::from_str(body)?
+    } else {
+        let wrapped = anchors.parse(body, "")?;
+        // safe because the body was wrapped under this key
+        wrapped.get(BODY).cloned().unwrap()
+    };
+    let value = convert(value, "")?;
+    anchors.collect(body)?;
+    Ok(value)
+}
+
+/// The names of the anchors `body` defines, like `base` for `&base`.
+fn names(body: &str) -> Vec<String> {
+    let mut names = vec![];
+    for Token(_, token) in Scanner::new(body.chars()) {
+        if let TokenType::Anchor(name) = token {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Converts `value`, which is at `pointer`, following the rules of `parse`.
+fn convert(value: serde_yaml::Value, pointer: &str) -> Result<serde_json::Value, Error> {
+    let shown = if pointer == "" { "/" } else { pointer };
+    Ok(match value {
+        serde_yaml::Value::Null => serde_json::Value::Null,
+        serde_yaml::Value::Bool(b) => serde_json::Value::Bool(b),
+        serde_yaml::Value::String(s) => serde_json::Value::String(s),
+        serde_yaml::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                serde_json::Value::from(i)
+            } else if let Some(u) = n.as_u64() {
+                serde_json::Value::from(u)
+            } else {
+                let f = n.as_f64().unwrap_or(std::f64::NAN);
+                let number = serde_json::Number::from_f64(f)
+                    .ok_or_else(|| err_msg(format!("json has no {}: {}", float(f), shown)))?;
+                serde_json::Value::Number(number)
+            }
+        }
+        serde_yaml::Value::Sequence(items) => {
+            let mut out = vec![];
+            for (i, item) in items.into_iter().enumerate() {
+                out.push(convert(item, &format!("{}/{}", pointer, i))?);
+            }
+            serde_json::Value::Array(out)
+        }
+        serde_yaml::Value::Mapping(map) => {
+            let mut out = serde_json::Map::new();
+            let mut merged = vec![];
+            for (k, v) in map {
+                let key = match k {
+                    serde_yaml::Value::String(k) => k,
+                    other => {
+                        return Err(err_msg(format!(
+                            "yaml map keys must be strings, found {}: {}",
+                            describe(&other),
+                            shown
+                        )))
+                    }
+                };
+                let pointer = format!("{}/{}", pointer, schema::escape(&key));
+                if key == "<<" {
+                    merged.push((v, pointer));
+                    continue;
+                }
+                out.insert(key, convert(v, &pointer)?);
+            }
+            for (v, pointer) in merged {
+                let maps = match convert(v, &pointer)? {
+                    serde_json::Value::Array(items) => items,
+                    item => vec![item],
+                };
+                for map in maps {
+                    let map = match map {
+                        serde_json::Value::Object(map) => map,
+                        _ => return Err(err_msg(format!("<< needs a map: {}", pointer))),
+                    };
+                    for (k, v) in map {
+                        out.entry(k).or_insert(v);
+                    }
+                }
+            }
+            serde_json::Value::Object(out)
+        }
+    })
+}
+
+fn float(f: f64) -> &'static str {
+    if f.is_nan() {
+        ".nan"
+    } else if f > 0.0 {
+        ".inf"
+    } else {
+        "-.inf"
+    }
+}
+
+fn describe(key: &serde_yaml::Value) -> String {
+    match key {
+        serde_yaml::Value::Null => "null".into(),
+        serde_yaml::Value::Bool(b) => format!("`{}`", b),
+        serde_yaml::Value::Number(n) => match n.as_i64() {
+            Some(i) => format!("`{}`", i),
+            None => format!("`{}`", n.as_f64().unwrap_or(std::f64::NAN)),
+        },
+        serde_yaml::Value::String(s) => format!("`{}`", s),
+        serde_yaml::Value::Sequence(_) => "a list".into(),
+        serde_yaml::Value::Mapping(_) => "a map".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Anchors;
+    use crate::context::StaticContext;
+    use crate::convert::convert;
+
+    #[test]
+    fn parse() {
+        let mut anchors = Anchors::new();
+        let mut parse = |body: &str| super::parse(body, &mut anchors).map_err(|e| e.to_string());
+
+        assert_eq!(
+            parse("a: 1\nb: 1.0\nc: !!str 2\nd: !custom x\ne: ~\n"),
+            Ok(json!({"a": 1, "b": 1.0, "c": "2", "d": "x", "e": null}))
+        );
+        assert_eq!(
+            parse("codes:\n  200: ok\n"),
+            Err("yaml map keys must be strings, found `200`: /codes".into())
+        );
+        assert_eq!(
+            parse("? [a, b]\n: c\n"),
+            Err("yaml map keys must be strings, found a list: /".into())
+        );
+        assert_eq!(
+            parse("a:\n  - .nan\n"),
+            Err("json has no .nan: /a/0".into())
+        );
+
+        assert_eq!(
+            parse("base: &base {size: 10, color: red}\nlist: &list [1, 2]\n"),
+            Ok(json!({"base": {"size": 10, "color": "red"}, "list": [1, 2]}))
+        );
+        assert_eq!(
+            parse("big:\n  <<: *base\n  size: 20\nitems: *list\n"),
+            Ok(json!({"big": {"size": 20, "color": "red"}, "items": [1, 2]}))
+        );
+        assert_eq!(
+            parse("<<: [*base, {shape: round}]\n"),
+            Ok(json!({"size": 10, "color": "red", "shape": "round"}))
+        );
+        assert!(parse("a: *missing\n").is_err());
+        assert_eq!(
+            parse("show: tom &jerry\n"),
+            Ok(json!({"show": "tom &jerry"}))
+        );
+        assert_eq!(
+            parse("%YAML 1.2\n---\nsize: *list\n"),
+            Ok(json!({"size": [1, 2]}))
+        );
+        assert_eq!(
+            parse("--- {size: 1}\n"),
+            Ok(json!({"size": 1}))
+        );
+        let bad = "a: 1\nb: [2\n";
+        assert_eq!(
+            parse(bad),
+            Err(super::parse(bad, &mut Anchors::new()).unwrap_err().to_string())
+        );
+
+        let ctx = StaticContext::new("page.json", r#"{"a": {"$ref": "a"}, "b": {"$ref": "b"}}"#);
+        let txt = "-- $page\n-- @a\nbox: &box {size: 10}\n-- @b\n<<: *box\ncolor: red\n";
+        assert_eq!(
+            convert(txt, &ctx).unwrap(),
+            json!({"a": {"box": {"size": 10}}, "b": {"size": 10, "color": "red"}})
+        );
+
+        let txt = "-- $page\n-- @a if:env.a\nbox: &box {size: 10}\n-- @b\n<<: *box\n";
+        assert!(convert(txt, &ctx).is_err());
+    }
+}